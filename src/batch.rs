@@ -0,0 +1,306 @@
+use std::time::{Duration, Instant};
+
+use irc::proto::{Command, Message};
+use irc::proto::message::Tag;
+
+use tags::MessageTags;
+
+// a batch that never ends shouldn't be able to eat all our memory, past
+// any of these it is handed over with what it has and forgotten about
+const MAX_BATCH_LINES: usize = 10_000;
+const MAX_OPEN_BATCHES: usize = 50;
+const BATCH_TIMEOUT: Duration = Duration::from_secs(120);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchKind {
+    Netsplit,
+    Netjoin,
+    ChatHistory,
+    LabeledResponse,
+    Multiline,
+    Other(String),
+}
+
+impl BatchKind {
+    // the irc crate upper cases any type it doesn't know
+    pub fn parse(kind: &str) -> BatchKind {
+        match kind.to_lowercase().as_str() {
+            "netsplit" => BatchKind::Netsplit,
+            "netjoin" => BatchKind::Netjoin,
+            "chathistory" | "draft/chathistory" => BatchKind::ChatHistory,
+            "labeled-response" | "draft/labeled-response" => BatchKind::LabeledResponse,
+            "draft/multiline" | "multiline" => BatchKind::Multiline,
+            _ => BatchKind::Other(String::from(kind)),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        match *self {
+            BatchKind::Netsplit => "netsplit",
+            BatchKind::Netjoin => "netjoin",
+            BatchKind::ChatHistory => "chathistory",
+            BatchKind::LabeledResponse => "labeled-response",
+            BatchKind::Multiline => "draft/multiline",
+            BatchKind::Other(ref kind) => kind,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum BatchItem {
+    Message(Message),
+    Batch(Batch),
+}
+
+#[derive(Debug, Clone)]
+pub struct Batch {
+    pub reference: String,
+    pub kind: BatchKind,
+    pub params: Vec<String>,
+    // the tags on the line that opened it, where the label and msgid live
+    pub tags: MessageTags,
+    // messages and finished inner batches in the order they came in
+    pub items: Vec<BatchItem>,
+}
+
+impl Batch {
+    // only the ones directly inside this batch
+    pub fn messages(&self) -> impl Iterator<Item = &Message> {
+        self.items.iter().filter_map(|i| match *i {
+            BatchItem::Message(ref m) => Some(m),
+            BatchItem::Batch(_) => None,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct OpenBatch {
+    parent: Option<String>,
+    lines: usize,
+    started: Instant,
+    batch: Batch,
+}
+
+#[derive(Debug, Clone)]
+pub enum Buffered {
+    // part of an open batch, it comes back when the batch ends
+    Held,
+    // nothing to do with any batch we know about
+    Live(Message),
+    // the batch got too big, everything up to and including this message
+    Overflow(Batch),
+}
+
+// batches the server has started and not yet ended
+#[derive(Debug, Clone, Default)]
+pub struct Batches {
+    open: Vec<OpenBatch>,
+}
+
+impl Batches {
+    pub fn new() -> Batches {
+        Batches::default()
+    }
+
+    // an older batch that had to be given up to make room
+    pub fn start(&mut self, reference: &str, kind: &str, params: Vec<String>, tags: MessageTags, now: Instant) -> Option<Batch> {
+        let dropped = if self.open.len() >= MAX_OPEN_BATCHES {
+            Some(self.open.remove(0).batch)
+        } else {
+            None
+        };
+        // a batch inside another one is tagged with the outer batch
+        let parent = tags.batch().filter(|p| self.is_open(p)).map(String::from);
+        self.open.push(OpenBatch {
+            parent,
+            lines: 0,
+            started: now,
+            batch: Batch {
+                reference: String::from(reference),
+                kind: BatchKind::parse(kind),
+                params,
+                tags,
+                items: Vec::new(),
+            },
+        });
+        dropped
+    }
+
+    pub fn is_open(&self, reference: &str) -> bool {
+        self.open.iter().any(|o| o.batch.reference == reference)
+    }
+
+    // the BATCH lines themselves always come back so nesting can be tracked
+    pub fn buffer(&mut self, msg: Message) -> Buffered {
+        if let Command::BATCH(..) = msg.command {
+            return Buffered::Live(msg);
+        }
+        let reference = match msg.tags.as_ref().and_then(|tags| batch_tag(tags)) {
+            Some(reference) => reference,
+            None => return Buffered::Live(msg),
+        };
+        let pos = match self.open.iter().position(|o| o.batch.reference == reference) {
+            Some(pos) => pos,
+            None => return Buffered::Live(msg),
+        };
+        let open = &mut self.open[pos];
+        open.lines += 1;
+        open.batch.items.push(BatchItem::Message(msg));
+        if open.lines < MAX_BATCH_LINES {
+            return Buffered::Held;
+        }
+        Buffered::Overflow(self.open.remove(pos).batch)
+    }
+
+    // the finished batch, unless it belongs inside one that is still open
+    pub fn end(&mut self, reference: &str) -> Option<Batch> {
+        let pos = self.open.iter().position(|o| o.batch.reference == reference)?;
+        let done = self.open.remove(pos);
+        let parent = done.parent.as_ref().and_then(|p| self.open.iter_mut().find(|o| &o.batch.reference == p));
+        match parent {
+            Some(parent) => {
+                parent.batch.items.push(BatchItem::Batch(done.batch));
+                None
+            },
+            None => Some(done.batch),
+        }
+    }
+
+    // batches the server never ended, oldest first
+    pub fn expire(&mut self, now: Instant) -> Vec<Batch> {
+        let (expired, open): (Vec<OpenBatch>, Vec<OpenBatch>) = self.open.drain(..)
+            .partition(|o| now.duration_since(o.started) >= BATCH_TIMEOUT);
+        self.open = open;
+        expired.into_iter().map(|o| o.batch).collect()
+    }
+
+    pub fn clear(&mut self) {
+        self.open.clear();
+    }
+}
+
+fn batch_tag(tags: &[Tag]) -> Option<String> {
+    tags.iter()
+        .find(|Tag(key, _)| key == "batch")
+        .and_then(|Tag(_, value)| value.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn msg(line: &str) -> Message {
+        line.parse().expect("message")
+    }
+
+    fn start(batches: &mut Batches, reference: &str, kind: &str, parent: Option<&str>, now: Instant) -> Option<Batch> {
+        let tags = parent.map(|p| vec![Tag(String::from("batch"), Some(String::from(p)))]);
+        batches.start(reference, kind, Vec::new(), MessageTags::from(tags), now)
+    }
+
+    fn text(item: &BatchItem) -> String {
+        match *item {
+            BatchItem::Message(Message { command: Command::PRIVMSG(_, ref text), .. }) => text.clone(),
+            BatchItem::Message(_) => String::from("?"),
+            BatchItem::Batch(ref batch) => format!("[{}]", batch.reference),
+        }
+    }
+
+    #[test]
+    fn kinds() {
+        assert_eq!(BatchKind::parse("NETSPLIT"), BatchKind::Netsplit);
+        assert_eq!(BatchKind::parse("draft/chathistory"), BatchKind::ChatHistory);
+        assert_eq!(BatchKind::parse("draft/multiline").as_str(), "draft/multiline");
+        assert_eq!(BatchKind::parse("example.com/thing"), BatchKind::Other(String::from("example.com/thing")));
+    }
+
+    #[test]
+    fn untagged_lines_are_live() {
+        let mut batches = Batches::new();
+        start(&mut batches, "a", "chathistory", None, Instant::now());
+        assert!(matches!(batches.buffer(msg(":n!u@h PRIVMSG #c :hi")), Buffered::Live(_)));
+        assert!(matches!(batches.buffer(msg("@batch=zz :n!u@h PRIVMSG #c :hi")), Buffered::Live(_)));
+        assert!(matches!(batches.buffer(msg("@batch=a BATCH +b netjoin")), Buffered::Live(_)));
+        assert!(matches!(batches.buffer(msg("@batch=a :n!u@h PRIVMSG #c :hi")), Buffered::Held));
+    }
+
+    #[test]
+    fn nested() {
+        let mut batches = Batches::new();
+        let now = Instant::now();
+        assert!(start(&mut batches, "outer", "chathistory", None, now).is_none());
+        batches.buffer(msg("@batch=outer :n!u@h PRIVMSG #c :one"));
+        start(&mut batches, "inner", "draft/multiline", Some("outer"), now);
+        batches.buffer(msg("@batch=inner :n!u@h PRIVMSG #c :two"));
+        batches.buffer(msg("@batch=outer :n!u@h PRIVMSG #c :three"));
+        // the inner batch ends up inside the outer one
+        assert!(batches.end("inner").is_none());
+        assert!(batches.is_open("outer"));
+        let outer = batches.end("outer").expect("outer");
+        assert_eq!(outer.kind, BatchKind::ChatHistory);
+        assert_eq!(outer.items.iter().map(text).collect::<Vec<_>>(), vec!["one", "three", "[inner]"]);
+        assert_eq!(outer.messages().count(), 2);
+        match outer.items[2] {
+            BatchItem::Batch(ref inner) => {
+                assert_eq!(inner.kind, BatchKind::Multiline);
+                assert_eq!(inner.items.iter().map(text).collect::<Vec<_>>(), vec!["two"]);
+            },
+            _ => panic!("expected the inner batch"),
+        }
+        assert!(batches.end("outer").is_none());
+    }
+
+    #[test]
+    fn parent_already_closed() {
+        let mut batches = Batches::new();
+        let now = Instant::now();
+        start(&mut batches, "outer", "chathistory", None, now);
+        start(&mut batches, "inner", "draft/multiline", Some("outer"), now);
+        batches.end("outer").expect("outer");
+        assert!(batches.end("inner").is_some());
+    }
+
+    #[test]
+    fn overflow() {
+        let mut batches = Batches::new();
+        start(&mut batches, "a", "chathistory", None, Instant::now());
+        for _ in 1..MAX_BATCH_LINES {
+            assert!(matches!(batches.buffer(msg("@batch=a :n!u@h PRIVMSG #c :hi")), Buffered::Held));
+        }
+        match batches.buffer(msg("@batch=a :n!u@h PRIVMSG #c :last")) {
+            Buffered::Overflow(batch) => {
+                assert_eq!(batch.items.len(), MAX_BATCH_LINES);
+                assert_eq!(batch.items.last().map(text).as_deref(), Some("last"));
+            },
+            _ => panic!("expected an overflow"),
+        }
+        // the rest of the batch is handled as it comes
+        assert!(!batches.is_open("a"));
+        assert!(matches!(batches.buffer(msg("@batch=a :n!u@h PRIVMSG #c :hi")), Buffered::Live(_)));
+    }
+
+    #[test]
+    fn too_many_open() {
+        let mut batches = Batches::new();
+        let now = Instant::now();
+        for i in 0..MAX_OPEN_BATCHES {
+            assert!(start(&mut batches, &i.to_string(), "chathistory", None, now).is_none());
+        }
+        let dropped = start(&mut batches, "new", "chathistory", None, now).expect("dropped");
+        assert_eq!(dropped.reference, "0");
+        assert!(batches.is_open("new"));
+    }
+
+    #[test]
+    fn expire() {
+        let mut batches = Batches::new();
+        let now = Instant::now();
+        start(&mut batches, "old", "chathistory", None, now);
+        start(&mut batches, "new", "chathistory", None, now + BATCH_TIMEOUT / 2);
+        assert!(batches.expire(now + BATCH_TIMEOUT / 2).is_empty());
+        let expired = batches.expire(now + BATCH_TIMEOUT);
+        assert_eq!(expired.iter().map(|b| b.reference.as_str()).collect::<Vec<_>>(), vec!["old"]);
+        assert!(batches.is_open("new"));
+        assert!(!batches.is_open("old"));
+    }
+}
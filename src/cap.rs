@@ -0,0 +1,380 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use irc::proto::{CapSubCommand, Command};
+
+// keep each REQ well under the 512 byte line limit
+const MAX_REQ_LEN: usize = 400;
+
+const DEFAULT_WANTED: &[&str] = &[
+    "account-notify",
+    "account-tag",
+    "away-notify",
+    "batch",
+    "cap-notify",
+    "chghost",
+    "draft/multiline",
+    "echo-message",
+    "extended-join",
+    "invite-notify",
+    "labeled-response",
+    "message-tags",
+    "multi-prefix",
+    "server-time",
+    "userhost-in-names",
+];
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CapState {
+    NotStarted,
+    Listing,
+    Requesting,
+    Done,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CapNegotiator {
+    state: CapState,
+    wanted: BTreeSet<String>,
+    available: BTreeMap<String, Option<String>>,
+    pending: BTreeSet<String>,
+    enabled: BTreeSet<String>,
+    refused: BTreeSet<String>,
+    hold_for: BTreeSet<String>,
+    held: bool,
+    #[serde(skip)]
+    listed: Option<BTreeSet<String>>,
+}
+
+impl Default for CapNegotiator {
+    fn default() -> Self {
+        CapNegotiator::new(DEFAULT_WANTED.iter().map(|c| String::from(*c)).collect())
+    }
+}
+
+impl CapNegotiator {
+    pub fn new(wanted: Vec<String>) -> CapNegotiator {
+        CapNegotiator {
+            state: CapState::NotStarted,
+            wanted: wanted.into_iter().collect(),
+            available: BTreeMap::new(),
+            pending: BTreeSet::new(),
+            enabled: BTreeSet::new(),
+            refused: BTreeSet::new(),
+            hold_for: BTreeSet::new(),
+            held: false,
+            listed: None,
+        }
+    }
+
+    pub fn want(&mut self, cap: &str) {
+        self.wanted.insert(String::from(cap));
+    }
+
+    // keep registration open after this capability is acknowledged until
+    // release is called, sasl needs this to log in before CAP END
+    pub fn hold_for(&mut self, cap: &str) {
+        self.hold_for.insert(String::from(cap));
+    }
+
+    pub fn is_held(&self) -> bool {
+        self.held
+    }
+
+    pub fn release(&mut self) -> Vec<Command> {
+        self.held = false;
+        self.finish()
+    }
+
+    pub fn state(&self) -> CapState {
+        self.state
+    }
+
+    pub fn is_enabled(&self, cap: &str) -> bool {
+        self.enabled.contains(cap)
+    }
+
+    pub fn is_available(&self, cap: &str) -> bool {
+        self.available.contains_key(cap)
+    }
+
+    // the value advertised with a capability in CAP LS 302, e.g. the
+    // mechanisms listed for sasl
+    pub fn value(&self, cap: &str) -> Option<&str> {
+        self.available.get(cap).and_then(|v| v.as_deref())
+    }
+
+    pub fn enabled(&self) -> Vec<String> {
+        self.enabled.iter().cloned().collect()
+    }
+
+    pub fn start(&mut self) -> Command {
+        self.state = CapState::Listing;
+        self.available.clear();
+        self.pending.clear();
+        self.enabled.clear();
+        self.refused.clear();
+        self.held = false;
+        Command::CAP(None, CapSubCommand::LS, Some(String::from("302")), None)
+    }
+
+    // a new connection starts negotiating from scratch, what we want stays
+    pub fn reset(&mut self) {
+        self.state = CapState::NotStarted;
+        self.available.clear();
+        self.pending.clear();
+        self.enabled.clear();
+        self.refused.clear();
+        self.held = false;
+        self.listed = None;
+    }
+
+    // registration finished without the server ever answering CAP LS
+    pub fn registered(&mut self) {
+        self.state = CapState::Done;
+        self.held = false;
+        self.pending.clear();
+    }
+
+    // feed one CAP line from the server, returns whatever needs to be sent back
+    pub fn handle(&mut self, sub_command: &CapSubCommand, caps: &str, more: bool) -> Vec<Command> {
+        match *sub_command {
+            CapSubCommand::LS => {
+                self.available.extend(parse_caps(caps));
+                if more || self.state != CapState::Listing {
+                    return Vec::new();
+                }
+                self.state = CapState::Requesting;
+                let wanted = self.wanted_available();
+                let mut ret = self.request(wanted);
+                ret.extend(self.finish());
+                ret
+            },
+            CapSubCommand::ACK => {
+                for cap in caps.split_whitespace() {
+                    if let Some(cap) = cap.strip_prefix('-') {
+                        self.enabled.remove(cap);
+                        self.pending.remove(cap);
+                    } else {
+                        if self.state == CapState::Requesting && self.hold_for.contains(cap) {
+                            self.held = true;
+                        }
+                        self.enabled.insert(String::from(cap));
+                        self.pending.remove(cap);
+                    }
+                }
+                self.finish()
+            },
+            CapSubCommand::NAK => {
+                // don't ask again for something the server already turned down
+                for cap in caps.split_whitespace() {
+                    let cap = cap.trim_start_matches('-');
+                    self.pending.remove(cap);
+                    self.refused.insert(String::from(cap));
+                }
+                self.finish()
+            },
+            CapSubCommand::NEW => {
+                self.available.extend(parse_caps(caps));
+                let wanted = self.wanted_available();
+                self.request(wanted)
+            },
+            CapSubCommand::DEL => {
+                for cap in caps.split_whitespace() {
+                    self.available.remove(cap);
+                    self.enabled.remove(cap);
+                    self.pending.remove(cap);
+                }
+                Vec::new()
+            },
+            CapSubCommand::LIST => {
+                // LIST is the server's view of what is enabled, so it replaces ours
+                // once the last line arrives
+                let mut listed = self.listed.take().unwrap_or_default();
+                listed.extend(caps.split_whitespace().map(String::from));
+                if more {
+                    self.listed = Some(listed);
+                } else {
+                    self.enabled = listed;
+                }
+                Vec::new()
+            },
+            _ => Vec::new(),
+        }
+    }
+
+    fn wanted_available(&self) -> Vec<String> {
+        self.wanted.iter()
+            .filter(|c| self.available.contains_key(*c))
+            .filter(|c| !self.enabled.contains(*c) && !self.pending.contains(*c) && !self.refused.contains(*c))
+            .cloned()
+            .collect()
+    }
+
+    fn request(&mut self, caps: Vec<String>) -> Vec<Command> {
+        let mut ret = Vec::new();
+        let mut line = String::new();
+        for cap in caps {
+            if !line.is_empty() && line.len() + cap.len() + 1 > MAX_REQ_LEN {
+                ret.push(Command::CAP(None, CapSubCommand::REQ, None, Some(line.clone())));
+                line.clear();
+            }
+            if !line.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(&cap);
+            self.pending.insert(cap);
+        }
+        if !line.is_empty() {
+            ret.push(Command::CAP(None, CapSubCommand::REQ, None, Some(line)));
+        }
+        ret
+    }
+
+    // CAP END is only sent once, while registration is waiting on us
+    fn finish(&mut self) -> Vec<Command> {
+        if self.state != CapState::Requesting || !self.pending.is_empty() || self.held {
+            return Vec::new();
+        }
+        self.state = CapState::Done;
+        vec![Command::CAP(None, CapSubCommand::END, None, None)]
+    }
+}
+
+fn parse_caps(caps: &str) -> Vec<(String, Option<String>)> {
+    caps.split_whitespace().map(|cap| {
+        let mut parts = cap.splitn(2, '=');
+        let name = String::from(parts.next().unwrap_or(""));
+        let value = parts.next().filter(|v| !v.is_empty()).map(String::from);
+        (name, value)
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use irc::proto::Message;
+
+    fn lines(cmds: Vec<Command>) -> Vec<String> {
+        cmds.into_iter().map(|c| Message::from(c).to_string().trim_end().to_string()).collect()
+    }
+
+    fn negotiator(wanted: &[&str]) -> CapNegotiator {
+        let mut caps = CapNegotiator::new(wanted.iter().map(|c| String::from(*c)).collect());
+        caps.start();
+        caps
+    }
+
+    #[test]
+    fn ls_continuation() {
+        let mut caps = negotiator(&["batch", "sasl"]);
+        assert!(caps.handle(&CapSubCommand::LS, "batch away-notify", true).is_empty());
+        assert_eq!(caps.state(), CapState::Listing);
+        let sent = lines(caps.handle(&CapSubCommand::LS, "sasl=PLAIN,EXTERNAL", false));
+        assert_eq!(sent, vec!["CAP REQ :batch sasl"]);
+        assert_eq!(caps.state(), CapState::Requesting);
+        assert!(caps.is_available("away-notify"));
+        assert_eq!(caps.value("sasl"), Some("PLAIN,EXTERNAL"));
+        assert_eq!(caps.value("batch"), None);
+    }
+
+    #[test]
+    fn nothing_wanted_ends_at_once() {
+        let mut caps = negotiator(&["batch"]);
+        let sent = lines(caps.handle(&CapSubCommand::LS, "away-notify", false));
+        assert_eq!(sent, vec!["CAP END"]);
+        assert_eq!(caps.state(), CapState::Done);
+    }
+
+    #[test]
+    fn end_waits_for_every_answer() {
+        let mut caps = negotiator(&["batch", "chghost", "sasl"]);
+        caps.handle(&CapSubCommand::LS, "batch chghost sasl", false);
+        assert!(caps.handle(&CapSubCommand::ACK, "batch", false).is_empty());
+        assert!(caps.handle(&CapSubCommand::NAK, "sasl", false).is_empty());
+        assert_eq!(caps.state(), CapState::Requesting);
+        let sent = lines(caps.handle(&CapSubCommand::ACK, "chghost", false));
+        assert_eq!(sent, vec!["CAP END"]);
+        assert_eq!(caps.state(), CapState::Done);
+        assert_eq!(caps.enabled(), vec!["batch", "chghost"]);
+        assert!(!caps.is_enabled("sasl"));
+        // a late answer doesn't end registration twice
+        assert!(caps.handle(&CapSubCommand::ACK, "-batch", false).is_empty());
+        assert!(!caps.is_enabled("batch"));
+    }
+
+    #[test]
+    fn new_and_del() {
+        let mut caps = negotiator(&["batch", "chghost", "sasl"]);
+        caps.handle(&CapSubCommand::LS, "batch sasl", false);
+        caps.handle(&CapSubCommand::ACK, "batch", false);
+        caps.handle(&CapSubCommand::NAK, "sasl", false);
+        assert_eq!(caps.state(), CapState::Done);
+
+        // refused caps aren't asked for again, and nothing sends another END
+        let sent = lines(caps.handle(&CapSubCommand::NEW, "chghost sasl away-notify", false));
+        assert_eq!(sent, vec!["CAP REQ :chghost"]);
+        assert!(caps.handle(&CapSubCommand::ACK, "chghost", false).is_empty());
+        assert!(caps.is_enabled("chghost"));
+
+        assert!(caps.handle(&CapSubCommand::DEL, "chghost", false).is_empty());
+        assert!(!caps.is_enabled("chghost"));
+        assert!(!caps.is_available("chghost"));
+        // when it comes back it gets asked for again
+        let sent = lines(caps.handle(&CapSubCommand::NEW, "chghost", false));
+        assert_eq!(sent, vec!["CAP REQ :chghost"]);
+    }
+
+    #[test]
+    fn sasl_holds_end() {
+        let mut caps = negotiator(&["batch", "sasl"]);
+        caps.hold_for("sasl");
+        caps.handle(&CapSubCommand::LS, "batch sasl=PLAIN", false);
+        assert!(caps.handle(&CapSubCommand::ACK, "batch sasl", false).is_empty());
+        assert!(caps.is_held());
+        assert_eq!(caps.state(), CapState::Requesting);
+        let sent = lines(caps.release());
+        assert_eq!(sent, vec!["CAP END"]);
+        assert!(!caps.is_held());
+        assert_eq!(caps.state(), CapState::Done);
+        assert!(caps.release().is_empty());
+    }
+
+    #[test]
+    fn hold_waits_for_other_answers() {
+        let mut caps = negotiator(&["batch", "sasl"]);
+        caps.hold_for("sasl");
+        caps.handle(&CapSubCommand::LS, "batch sasl", false);
+        caps.handle(&CapSubCommand::ACK, "sasl", false);
+        // logging in finished before the other cap was answered
+        assert!(caps.release().is_empty());
+        let sent = lines(caps.handle(&CapSubCommand::ACK, "batch", false));
+        assert_eq!(sent, vec!["CAP END"]);
+    }
+
+    #[test]
+    fn sasl_after_registration_holds_nothing() {
+        let mut caps = negotiator(&["sasl"]);
+        caps.hold_for("sasl");
+        caps.handle(&CapSubCommand::LS, "batch", false);
+        assert_eq!(caps.state(), CapState::Done);
+        caps.handle(&CapSubCommand::NEW, "sasl", false);
+        caps.handle(&CapSubCommand::ACK, "sasl", false);
+        assert!(!caps.is_held());
+    }
+
+    #[test]
+    fn restart_forgets_the_old_connection() {
+        let mut caps = negotiator(&["batch"]);
+        caps.hold_for("batch");
+        caps.handle(&CapSubCommand::LS, "batch", false);
+        caps.handle(&CapSubCommand::ACK, "batch", false);
+        caps.reset();
+        assert_eq!(caps.state(), CapState::NotStarted);
+        assert!(!caps.is_held());
+        assert!(!caps.is_enabled("batch"));
+        caps.start();
+        let sent = lines(caps.handle(&CapSubCommand::LS, "batch", false));
+        assert_eq!(sent, vec!["CAP REQ :batch"]);
+    }
+}
@@ -0,0 +1,101 @@
+use std::fmt::{Display, Formatter, Result};
+
+#[derive(Debug, Clone, Copy, Default, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CaseMapping {
+    Ascii,
+    #[default]
+    Rfc1459,
+    StrictRfc1459,
+}
+
+impl CaseMapping {
+    // anything we don't know about gets the rfc1459 rules, which is what
+    // servers assumed before CASEMAPPING existed
+    pub fn from(name: &str) -> CaseMapping {
+        match name.to_lowercase().as_str() {
+            "ascii" => CaseMapping::Ascii,
+            "strict-rfc1459" => CaseMapping::StrictRfc1459,
+            _ => CaseMapping::Rfc1459,
+        }
+    }
+
+    pub fn fold_char(self, c: char) -> char {
+        match (self, c) {
+            (_, 'A'..='Z') => c.to_ascii_lowercase(),
+            (CaseMapping::Ascii, _) => c,
+            (_, '[') => '{',
+            (_, ']') => '}',
+            (_, '\\') => '|',
+            (CaseMapping::Rfc1459, '~') => '^',
+            _ => c,
+        }
+    }
+
+    pub fn fold(self, name: &str) -> String {
+        name.chars().map(|c| self.fold_char(c)).collect()
+    }
+
+    pub fn eq(self, lhs: &str, rhs: &str) -> bool {
+        lhs.len() == rhs.len() && lhs.chars().zip(rhs.chars()).all(|(l, r)| self.fold_char(l) == self.fold_char(r))
+    }
+
+    pub fn key(self, name: &str) -> CaseKey {
+        CaseKey(self.fold(name))
+    }
+}
+
+// a nick or channel name folded with the server's case mapping, only for
+// finding things, the name to show lives next to it
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+#[serde(transparent)]
+pub struct CaseKey(String);
+
+impl CaseKey {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Display for CaseKey {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_names() {
+        assert_eq!(CaseMapping::from("ascii"), CaseMapping::Ascii);
+        assert_eq!(CaseMapping::from("RFC1459"), CaseMapping::Rfc1459);
+        assert_eq!(CaseMapping::from("strict-rfc1459"), CaseMapping::StrictRfc1459);
+        assert_eq!(CaseMapping::from("rfc7613"), CaseMapping::Rfc1459);
+    }
+
+    #[test]
+    fn folding() {
+        assert_eq!(CaseMapping::Ascii.fold("Nick[]\\~^"), "nick[]\\~^");
+        assert_eq!(CaseMapping::Rfc1459.fold("Nick[]\\~^"), "nick{}|^^");
+        assert_eq!(CaseMapping::StrictRfc1459.fold("Nick[]\\~^"), "nick{}|~^");
+    }
+
+    #[test]
+    fn tilde_and_caret() {
+        assert!(CaseMapping::Rfc1459.eq("a~b", "A^B"));
+        assert!(!CaseMapping::StrictRfc1459.eq("a~b", "A^B"));
+        assert!(!CaseMapping::Ascii.eq("a~b", "A^B"));
+        assert_eq!(CaseMapping::Rfc1459.key("Dan~"), CaseMapping::Rfc1459.key("dan^"));
+        assert_ne!(CaseMapping::StrictRfc1459.key("Dan~"), CaseMapping::StrictRfc1459.key("dan^"));
+        assert!(CaseMapping::StrictRfc1459.eq("[Dan]", "{dan}"));
+        assert!(!CaseMapping::Ascii.eq("[Dan]", "{dan}"));
+    }
+
+    #[test]
+    fn eq_needs_the_same_length() {
+        assert!(!CaseMapping::Rfc1459.eq("dan", "dan_"));
+        assert!(CaseMapping::Rfc1459.eq("", ""));
+    }
+}
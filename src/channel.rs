@@ -1,62 +1,100 @@
-use std::collections::{HashMap};
-use irc::client::prelude::*;
+use std::collections::{HashMap, VecDeque};
+use std::mem;
+use std::time::{SystemTime, UNIX_EPOCH, Duration};
+
+use casemap::{CaseKey, CaseMapping};
+use error::ServerErrorKind;
+use format::{self, Span};
+use hostmask::Hostmask;
+use isupport::ModeKind;
+use mask::Mask;
+use modes::{self, ChannelModes, ListEntry};
+use tags::MessageTags;
+use who::WhoReply;
+
+// users who have left are remembered until the channel grows past this
+const MAX_USERS_KEPT: usize = 2000;
+// older topics past this are forgotten
+const MAX_TOPIC_HISTORY: usize = 50;
 
 #[derive(Debug, Clone, Serialize)]
 pub struct Channel {
     name: String,
-    topic: String,
-    users: HashMap<String, ChannelUser>,
+    topic: Topic,
+    topic_history: VecDeque<Topic>,
+    users: HashMap<CaseKey, ChannelUser>,
     messages: Vec<ChannelMessage>,
-    status: Vec<ChannelStatus>
+    modes: ChannelModes,
+    membership: Membership,
+    #[serde(skip)]
+    case_mapping: CaseMapping,
 }
 
-#[derive(Debug, Clone, Serialize, Eq, PartialEq)]
-pub enum ChannelStatus {
-    Ban(String),
-    Exception,
-    Limit,
-    InviteOnly,
-    InviteException,
-    Key,
-    Moderated,
-    RegisteredOnly,
-    Secret,
-    ProtectedTopic,
-    NoExternalMessages,
-    Founder(String)
+// an empty text means the channel has no topic
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct Topic {
+    pub text: String,
+    pub set_by: Option<String>,
+    pub set_at: Option<u64>,
 }
 
-impl ChannelStatus {
-    fn from(mode: ChannelMode, arg: Option<String>) -> ChannelStatus {
-        match mode {
-            ChannelMode::Exception => ChannelStatus::Exception,
-            ChannelMode::Limit => ChannelStatus::Limit,
-            ChannelMode::InviteOnly => ChannelStatus::InviteOnly,
-            ChannelMode::InviteException => ChannelStatus::InviteException,
-            ChannelMode::Key => ChannelStatus::Key,
-            ChannelMode::Moderated => ChannelStatus::Moderated,
-            ChannelMode::RegisteredOnly => ChannelStatus::RegisteredOnly,
-            ChannelMode::Secret => ChannelStatus::Secret,
-            ChannelMode::ProtectedTopic => ChannelStatus::ProtectedTopic,
-            ChannelMode::NoExternalMessages => ChannelStatus::NoExternalMessages,
-            ChannelMode::Ban => ChannelStatus::Ban(arg.unwrap_or(String::From("Unknown users"))),
-            _ => ChannelStatus::
-        }
+impl Topic {
+    pub fn is_set(&self) -> bool {
+        !self.text.is_empty()
     }
 }
 
+#[derive(Debug, Clone, Copy, Serialize, Eq, PartialEq)]
+pub enum Membership {
+    Joining,
+    Joined,
+    Parted,
+    Kicked,
+    Failed(ServerErrorKind),
+}
+
+impl Membership {
+    pub fn is_present(self) -> bool {
+        self == Membership::Joined
+    }
+}
 
 #[derive(Debug, Clone, Serialize)]
 pub struct ChannelMessage {
     pub time_stamp: String,
     pub user_name: String,
     pub content: String,
+    pub kind: MessageKind,
+    pub plain: String,
+    pub spans: Vec<Span>,
+    pub msg_id: Option<String>,
+    pub account: Option<String>,
+    pub label: Option<String>,
+    // set when the message came inside a BATCH rather than live
+    pub batch: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Eq, PartialEq)]
+pub enum MessageKind {
+    Message,
+    Notice,
+    Action,
 }
 
 #[derive(Debug, Clone, Serialize)]
 pub struct ChannelUser {
     name: String,
-    status: Vec<UserStatus>,
+    user: Option<String>,
+    host: Option<String>,
+    account: Option<String>,
+    real_name: Option<String>,
+    away: bool,
+    oper: bool,
+    hop_count: Option<u32>,
+    membership: Membership,
+    // prefix modes like 'o' and 'v', highest rank first
+    privileges: Vec<char>,
+    modes: Vec<UserStatus>,
 }
 #[derive(Debug, Clone, Serialize)]
 pub enum UserStatus {
@@ -71,88 +109,344 @@ pub enum UserStatus {
     Unknown(char),
 }
 
+impl ChannelMessage {
+    pub fn new(user_name: String, content: String, kind: MessageKind) -> ChannelMessage {
+        let time_stamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::new(0, 0)).as_secs();
+        ChannelMessage {
+            time_stamp: format!("{}", time_stamp),
+            user_name,
+            plain: format::strip(&content),
+            spans: format::parse(&content),
+            content,
+            kind,
+            msg_id: None,
+            account: None,
+            label: None,
+            batch: None,
+        }
+    }
+
+    pub fn with_tags(mut self, tags: &MessageTags) -> ChannelMessage {
+        // replayed and delayed messages carry the time they were really sent
+        if let Some(time_stamp) = tags.server_time() {
+            self.time_stamp = format!("{}", time_stamp);
+        }
+        self.msg_id = tags.msg_id().map(String::from);
+        self.account = tags.account().map(String::from);
+        self.label = tags.label().map(String::from);
+        self.batch = tags.batch().map(String::from);
+        self
+    }
+}
+
+impl Default for Channel {
+    fn default() -> Self {
+        Channel::new()
+    }
+}
+
 impl Channel {
     pub fn new() -> Self {
+        Channel::with_name("")
+    }
+
+    pub fn with_name(name: &str) -> Self {
         Channel {
-            name: String::new(),
-            topic: String::new(),
+            name: String::from(name),
+            topic: Topic::default(),
+            topic_history: VecDeque::new(),
             users: HashMap::new(),
             messages: Vec::new(),
+            modes: ChannelModes::new(),
+            membership: Membership::Joining,
+            case_mapping: CaseMapping::default(),
         }
     }
 
-    pub fn add_users(&mut self, text: &str) -> u32 {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    // the server may send the same channel with different casing, show
+    // whatever it sent last
+    pub fn set_name(&mut self, name: &str) {
+        if self.name != name {
+            self.name = String::from(name);
+        }
+    }
+
+    pub fn set_case_mapping(&mut self, case_mapping: CaseMapping) {
+        if self.case_mapping == case_mapping {
+            return;
+        }
+        self.case_mapping = case_mapping;
+        let users = self.users.drain().map(|(_, u)| (case_mapping.key(&u.name), u)).collect();
+        self.users = users;
+    }
+
+    fn key(&self, username: &str) -> CaseKey {
+        self.case_mapping.key(username)
+    }
+
+    // a RPL_NAMREPLY line, each entry is the user's prefix symbols then their
+    // nick, or nick!user@host with userhost-in-names. Without multi-prefix
+    // only the highest prefix is sent so anything below it is left alone.
+    pub fn add_users(&mut self, text: &str, prefixes: &[(char, char)], multi_prefix: bool) -> u32 {
         let mut count = 0;
-        for un in text.split(" ") {
-            match self.users.insert(String::from(un), ChannelUser::with_name(&un)) {
-                Some(_) => (),
-                None => count += 1,
+        for entry in text.split(' ').filter(|e| !e.is_empty()) {
+            let mut privileges = Vec::new();
+            let mut rest = entry;
+            while let Some(&(mode, _)) = rest.chars().next().and_then(|c| prefixes.iter().find(|p| p.1 == c)) {
+                privileges.push(mode);
+                rest = &rest[rest.chars().next().map(char::len_utf8).unwrap_or(1)..];
+            }
+            let mask = Hostmask::parse(rest);
+            if mask.nick.is_empty() {
+                continue;
+            }
+            if self.join_user(&mask) {
+                count += 1;
+            }
+            let key = self.key(&mask.nick);
+            if let Some(u) = self.users.get_mut(&key) {
+                u.set_privileges(privileges, prefixes, multi_prefix);
             }
         }
         count
     }
 
-    pub fn add_message(&mut self, msg: ChannelMessage) {
-        self.messages.push(msg);
+    pub fn user(&self, username: &str) -> Option<&ChannelUser> {
+        self.users.get(&self.key(username))
     }
 
-    pub fn set_topic(&mut self, topic: &str) {
-        self.topic = String::from(topic);
+    pub fn join_user(&mut self, mask: &Hostmask) -> bool {
+        let key = self.key(&mask.nick);
+        let user = self.users.entry(key).or_insert_with(|| ChannelUser::with_name(&mask.nick));
+        user.name = mask.nick.clone();
+        user.set_host(mask);
+        let joined = !user.membership.is_present();
+        user.membership = Membership::Joined;
+        joined
     }
 
-    pub fn users(&self) -> Vec<String> {
-        self.users.iter().map(|u| u.1.name.to_string()).collect()
+    // JOIN, WHO and CHGHOST all tell us where a user is connecting from
+    pub fn set_user_host(&mut self, mask: &Hostmask) -> bool {
+        let key = self.key(&mask.nick);
+        match self.users.get_mut(&key) {
+            Some(user) => user.set_host(mask),
+            None => false,
+        }
+    }
+
+    // from extended-join, account-notify and WHO
+    pub fn set_user_account(&mut self, username: &str, account: Option<&str>) {
+        let key = self.key(username);
+        if let Some(user) = self.users.get_mut(&key) {
+            user.account = account.map(String::from);
+        }
     }
 
-    pub fn remove_user(&mut self, username: &str) -> bool {
-        match self.users.remove(username) {
-            Some(_) => true,
+    pub fn set_user_real_name(&mut self, username: &str, real_name: &str) {
+        let key = self.key(username);
+        if let Some(user) = self.users.get_mut(&key) {
+            user.real_name = Some(String::from(real_name));
+        }
+    }
+
+    // from away-notify
+    pub fn set_user_away(&mut self, username: &str, away: bool) {
+        let key = self.key(username);
+        if let Some(user) = self.users.get_mut(&key) {
+            user.away = away;
+        }
+    }
+
+    // WHO fills in everything NAMES leaves out. The prefixes are only
+    // used when the reply was about this channel
+    pub fn apply_who(&mut self, reply: &WhoReply, prefixes: &[(char, char)], multi_prefix: bool) -> bool {
+        let for_channel = reply.channel.as_ref().map(|c| self.case_mapping.eq(c, &self.name)).unwrap_or(false);
+        let key = self.key(&reply.nick);
+        let user = match self.users.get_mut(&key) {
+            Some(user) => user,
+            None => return false,
+        };
+        user.set_host(&reply.hostmask());
+        if reply.account.is_some() {
+            user.account = reply.account.clone();
+        }
+        if reply.real_name.is_some() {
+            user.real_name = reply.real_name.clone();
+        }
+        user.away = reply.away;
+        user.oper = reply.oper;
+        user.hop_count = reply.hop_count;
+        if for_channel {
+            let listed = reply.prefixes.chars()
+                .filter_map(|c| prefixes.iter().find(|p| p.1 == c).map(|p| p.0))
+                .collect();
+            user.set_privileges(listed, prefixes, multi_prefix);
+        }
+        true
+    }
+
+    // everyone here connecting from the same host
+    pub fn users_on_host(&self, host: &str) -> Vec<&ChannelUser> {
+        self.users.values()
+            .filter(|u| u.membership.is_present() && u.host() == Some(host))
+            .collect()
+    }
+
+    pub fn part_user(&mut self, username: &str, membership: Membership) -> bool {
+        let key = self.key(username);
+        let parted = match self.users.get_mut(&key) {
+            Some(user) if user.membership.is_present() => {
+                user.membership = membership;
+                true
+            },
+            _ => false,
+        };
+        if self.users.len() > MAX_USERS_KEPT {
+            self.prune_users();
+        }
+        parted
+    }
+
+    pub fn rename_user(&mut self, old: &str, new: &str) -> bool {
+        let key = self.key(old);
+        match self.users.remove(&key) {
+            Some(mut user) => {
+                let present = user.membership.is_present();
+                user.name = String::from(new);
+                self.users.insert(self.key(new), user);
+                present
+            },
             None => false,
         }
     }
 
-    pub fn chan_status(&mut self, mode: Mode<ChannelMode>) {
-        match mode {
-            Plus(mode, arg) => self.add_status(mode, arg),
-            Minus(mode, arg) => self.remove_status(mode, arg),
+    pub fn prune_users(&mut self) {
+        self.users.retain(|_, u| u.membership.is_present());
+    }
+
+    pub fn user_membership(&self, username: &str) -> Option<Membership> {
+        self.users.get(&self.key(username)).map(|u| u.membership)
+    }
+
+    pub fn membership(&self) -> Membership {
+        self.membership
+    }
+
+    pub fn set_membership(&mut self, membership: Membership) {
+        if !membership.is_present() {
+            self.users.clear();
         }
+        self.membership = membership;
     }
-    fn add_status(&mut self, mode: ChannelMode, arg: Option<String>) {
-        match mode {
-            ChannelMode::Founder => (),
-            ChannelMode::Admin => (),
-            ChannelMode::Oper => (),
-            ChannelMode::Halfop => (),
-            ChannelMode::Voice => (),
-            _ => {
-                self.status.push(ChannelStatus::from(mode));
-                self.status.dedup();
-            }
+
+    pub fn add_message(&mut self, msg: ChannelMessage) {
+        self.messages.push(msg);
+    }
+
+    pub fn topic(&self) -> &Topic {
+        &self.topic
+    }
+
+    // most recent first
+    pub fn topic_history(&self) -> &VecDeque<Topic> {
+        &self.topic_history
+    }
+
+    // hands back the topic that was replaced, None when the text didn't
+    // change and only who and when were filled in
+    pub fn set_topic(&mut self, text: &str, set_by: Option<&str>, set_at: Option<u64>) -> Option<Topic> {
+        if self.topic.text == text {
+            self.set_topic_setter(set_by, set_at);
+            return None;
+        }
+        let new = Topic {
+            text: String::from(text),
+            set_by: set_by.map(String::from),
+            set_at,
+        };
+        let old = mem::replace(&mut self.topic, new);
+        if old.is_set() {
+            self.topic_history.push_front(old.clone());
+            self.topic_history.truncate(MAX_TOPIC_HISTORY);
         }
+        Some(old)
     }
 
-    fn remove_status(&mut self, mode: ChannelMode, arg: Option<String>) {
-        match mode {
-            ChannelMode::Ban => {match arg {
-                Some(name) => self.status.push()
-            }},
-            ChannelMode::Founder => (),
-            ChannelMode::Admin => (),
-            ChannelMode::Oper => (),
-            ChannelMode::Halfop => (),
-            ChannelMode::Voice => (),
-            _ => {
-                self.status = self.staus.clone().into_iter().filter(|s| s != ChannelStatus::from(mode))
-            }
+    // RPL_TOPICWHOTIME comes after RPL_TOPIC on join
+    pub fn set_topic_setter(&mut self, set_by: Option<&str>, set_at: Option<u64>) {
+        if let Some(set_by) = set_by {
+            self.topic.set_by = Some(String::from(set_by));
+        }
+        if set_at.is_some() {
+            self.topic.set_at = set_at;
         }
     }
 
-    fn add_user_status(&mut self, mode: UserMode, arg: Option<String>) {
+    pub fn users(&self) -> Vec<String> {
+        self.users.values()
+            .filter(|u| u.membership.is_present())
+            .map(|u| u.name.to_string())
+            .collect()
+    }
+
+    pub fn modes(&self) -> &ChannelModes {
+        &self.modes
+    }
 
+    pub fn apply_mode(&mut self, adding: bool, mode: char, arg: Option<&str>, kind: Option<ModeKind>, set_by: &str, set_at: u64) -> bool {
+        self.modes.apply(adding, mode, arg, kind, set_by, set_at)
     }
 
-    fn remove_user_status(&mut self, mode: UserMode, arg: Option<String>) {
+    pub fn list_entry(&mut self, mode: char, entry: ListEntry) {
+        self.modes.list_entry(mode, entry);
+    }
 
+    pub fn end_list(&mut self, mode: char) {
+        self.modes.end_list(mode);
+    }
+
+    // the bans that hit a user, including extbans, empty if an exception
+    // lets them in anyway. banned_in answers $j for another channel
+    pub fn bans_for(&self, user: &ChannelUser, extban: Option<char>, banned_in: &dyn Fn(&str) -> bool) -> Vec<&ListEntry> {
+        let hits = |mode| self.modes.list(mode).iter()
+            .filter(|e| Mask::parse(&e.mask, extban).matches(user, self.case_mapping, banned_in))
+            .collect::<Vec<_>>();
+        if !hits(modes::EXCEPTION).is_empty() {
+            return Vec::new();
+        }
+        hits(modes::BAN)
+    }
+
+    // everyone here a mask would hit if it were set
+    pub fn hit_by(&self, mask: &Mask, banned_in: &dyn Fn(&str, &ChannelUser) -> bool) -> Vec<&ChannelUser> {
+        self.users.values()
+            .filter(|u| u.membership.is_present())
+            .filter(|u| mask.matches(u, self.case_mapping, &|channel| banned_in(channel, u)))
+            .collect()
+    }
+
+    pub fn clear_mode_settings(&mut self) {
+        self.modes.clear_settings();
+    }
+
+    pub fn add_privilege(&mut self, username: &str, mode: char, prefixes: &[(char, char)]) -> bool {
+        let key = self.key(username);
+        match self.users.get_mut(&key) {
+            Some(user) => user.add_privilege(mode, prefixes),
+            None => false,
+        }
+    }
+
+    pub fn remove_privilege(&mut self, username: &str, mode: char) -> bool {
+        let key = self.key(username);
+        match self.users.get_mut(&key) {
+            Some(user) => user.remove_privilege(mode),
+            None => false,
+        }
     }
 }
 
@@ -160,17 +454,119 @@ impl ChannelUser {
     pub fn with_name(name: &str) -> ChannelUser {
         ChannelUser {
             name: String::from(name),
-            chan_modes: vec![],
+            user: None,
+            host: None,
+            account: None,
+            real_name: None,
+            away: false,
+            oper: false,
+            hop_count: None,
+            membership: Membership::Joined,
+            privileges: vec![],
             modes: vec![],
         }
     }
 
-    pub fn add_status(&mut self, chan_mode: ChannelMode) {
-        self.chan_modes.push(level);
-        self.chan_modes.dedup();
+    // someone who may not be in the channel at all, for checking bans
+    // before an invite or unban
+    pub fn with_hostmask(mask: &Hostmask, account: Option<&str>) -> ChannelUser {
+        let mut user = ChannelUser::with_name(&mask.nick);
+        user.user = mask.user.clone();
+        user.host = mask.host.clone();
+        user.account = account.map(String::from);
+        user.membership = Membership::Parted;
+        user
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+
+    pub fn account(&self) -> Option<&str> {
+        self.account.as_deref()
+    }
+
+    pub fn real_name(&self) -> Option<&str> {
+        self.real_name.as_deref()
+    }
+
+    pub fn is_away(&self) -> bool {
+        self.away
+    }
+
+    pub fn is_oper(&self) -> bool {
+        self.oper
+    }
+
+    pub fn hop_count(&self) -> Option<u32> {
+        self.hop_count
+    }
+
+    pub fn hostmask(&self) -> Hostmask {
+        Hostmask::new(&self.name, self.user(), self.host())
+    }
+
+    // a bare nick says nothing about the user or host so what we had stays
+    fn set_host(&mut self, mask: &Hostmask) -> bool {
+        if !mask.is_complete() || (self.user == mask.user && self.host == mask.host) {
+            return false;
+        }
+        self.user = mask.user.clone();
+        self.host = mask.host.clone();
+        true
+    }
+
+    pub fn privileges(&self) -> &[char] {
+        &self.privileges
+    }
+
+    pub fn has_privilege(&self, mode: char) -> bool {
+        self.privileges.contains(&mode)
+    }
+
+    // the symbol shown in front of the nick, e.g. '@' for an op
+    pub fn prefix(&self, prefixes: &[(char, char)]) -> Option<char> {
+        self.privileges.first().and_then(|m| prefixes.iter().find(|p| p.0 == *m)).map(|p| p.1)
+    }
+
+    pub fn add_privilege(&mut self, mode: char, prefixes: &[(char, char)]) -> bool {
+        if self.has_privilege(mode) {
+            return false;
+        }
+        self.privileges.push(mode);
+        sort_privileges(&mut self.privileges, prefixes);
+        true
+    }
+
+    pub fn remove_privilege(&mut self, mode: char) -> bool {
+        let before = self.privileges.len();
+        self.privileges.retain(|m| *m != mode);
+        before != self.privileges.len()
     }
 
-    pub fn remove_status(&mut self, chan_mode: ChannelMode) {
-        self.chan_modes = self.chan_modes.clone().into_iter().filter(|c| c != &chan_mode).collect();
+    fn set_privileges(&mut self, listed: Vec<char>, prefixes: &[(char, char)], complete: bool) {
+        if complete {
+            self.privileges = listed;
+        } else {
+            let rank = |m: &char| prefixes.iter().position(|p| p.0 == *m).unwrap_or(prefixes.len());
+            let highest = listed.first().map(rank).unwrap_or(prefixes.len());
+            // nothing above what the server showed, and what it showed is definitely there
+            self.privileges.retain(|m| rank(m) > highest);
+            self.privileges.extend(listed);
+        }
+        sort_privileges(&mut self.privileges, prefixes);
+        self.privileges.dedup();
     }
+}
+
+fn sort_privileges(privileges: &mut [char], prefixes: &[(char, char)]) {
+    privileges.sort_by_key(|m| prefixes.iter().position(|p| p.0 == *m).unwrap_or(prefixes.len()));
 }
\ No newline at end of file
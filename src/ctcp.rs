@@ -0,0 +1,193 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter, Result};
+use std::time::{Duration, Instant};
+
+use chrono::Local;
+
+use casemap::{CaseKey, CaseMapping};
+
+const DELIM: char = '\u{1}';
+// a flood from many nicks at once still only gets this many answers
+const MAX_REPLIES: usize = 5;
+const REPLY_WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct Ctcp {
+    pub command: String,
+    pub params: Option<String>,
+}
+
+impl Ctcp {
+    pub fn new(command: &str, params: Option<&str>) -> Ctcp {
+        Ctcp {
+            command: command.to_uppercase(),
+            params: params.map(String::from),
+        }
+    }
+
+    pub fn parse(text: &str) -> Option<Ctcp> {
+        if !text.starts_with(DELIM) {
+            return None;
+        }
+        // some clients leave off the closing delimiter
+        let inner = text[1..].trim_end_matches(DELIM);
+        let mut parts = inner.splitn(2, ' ');
+        let command = parts.next().unwrap_or("");
+        if command.is_empty() {
+            return None;
+        }
+        Some(Ctcp::new(command, parts.next()))
+    }
+
+    pub fn is_action(&self) -> bool {
+        self.command == "ACTION"
+    }
+}
+
+impl Display for Ctcp {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match self.params {
+            Some(ref params) => write!(f, "{}{} {}{}", DELIM, self.command, params, DELIM),
+            None => write!(f, "{}{}{}", DELIM, self.command, DELIM),
+        }
+    }
+}
+
+pub struct CtcpResponder {
+    version: String,
+    source: String,
+    min_interval: Duration,
+    last_reply: HashMap<CaseKey, Instant>,
+    // every answer sent in the last REPLY_WINDOW, oldest first
+    recent: VecDeque<Instant>,
+    case_mapping: CaseMapping,
+}
+
+impl CtcpResponder {
+    pub fn new(version: &str, source: &str) -> CtcpResponder {
+        CtcpResponder {
+            version: String::from(version),
+            source: String::from(source),
+            min_interval: Duration::from_secs(10),
+            last_reply: HashMap::new(),
+            recent: VecDeque::new(),
+            case_mapping: CaseMapping::default(),
+        }
+    }
+
+    // the same nick spelled differently is still the same sender
+    pub fn set_case_mapping(&mut self, case_mapping: CaseMapping) {
+        self.case_mapping = case_mapping;
+        self.last_reply.clear();
+    }
+
+    pub fn set_min_interval(&mut self, min_interval: Duration) {
+        self.min_interval = min_interval;
+    }
+
+    pub fn reply(&mut self, from: &str, query: &Ctcp, now: Instant) -> Option<Ctcp> {
+        let answer = match query.command.as_str() {
+            "VERSION" => Ctcp::new("VERSION", Some(&self.version)),
+            "PING" => Ctcp::new("PING", query.params.as_deref()),
+            "TIME" => Ctcp::new("TIME", Some(&Local::now().to_rfc2822())),
+            "SOURCE" => Ctcp::new("SOURCE", Some(&self.source)),
+            "CLIENTINFO" => Ctcp::new("CLIENTINFO", Some("ACTION CLIENTINFO PING SOURCE TIME VERSION")),
+            _ => return None,
+        };
+        if !self.allow(from, now) {
+            return None;
+        }
+        Some(answer)
+    }
+
+    fn allow(&mut self, from: &str, now: Instant) -> bool {
+        let min_interval = self.min_interval;
+        self.last_reply.retain(|_, last| now.duration_since(*last) < min_interval);
+        while self.recent.front().map(|at| now.duration_since(*at) >= REPLY_WINDOW).unwrap_or(false) {
+            self.recent.pop_front();
+        }
+        let key = self.case_mapping.key(from);
+        if self.last_reply.contains_key(&key) || self.recent.len() >= MAX_REPLIES {
+            return false;
+        }
+        self.last_reply.insert(key, now);
+        self.recent.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ping() -> Ctcp {
+        Ctcp::new("PING", Some("123"))
+    }
+
+    #[test]
+    fn parse_and_display() {
+        assert_eq!(Ctcp::parse("\u{1}version\u{1}"), Some(Ctcp::new("VERSION", None)));
+        assert_eq!(Ctcp::parse("\u{1}ACTION waves"), Some(Ctcp::new("ACTION", Some("waves"))));
+        assert_eq!(Ctcp::parse("\u{1}\u{1}"), None);
+        assert_eq!(Ctcp::parse("hi"), None);
+        assert_eq!(ping().to_string(), "\u{1}PING 123\u{1}");
+        assert_eq!(Ctcp::new("VERSION", None).to_string(), "\u{1}VERSION\u{1}");
+    }
+
+    #[test]
+    fn answers() {
+        let start = Instant::now();
+        let mut responder = CtcpResponder::new("bot 1.0", "https://example.com/bot");
+        responder.set_min_interval(Duration::from_secs(0));
+        assert_eq!(responder.reply("a", &ping(), start), Some(ping()));
+        assert_eq!(responder.reply("b", &Ctcp::new("VERSION", None), start), Some(Ctcp::new("VERSION", Some("bot 1.0"))));
+        assert_eq!(responder.reply("c", &Ctcp::new("SOURCE", None), start).and_then(|c| c.params), Some(String::from("https://example.com/bot")));
+        // nothing to say doesn't use up a reply
+        assert_eq!(responder.reply("d", &Ctcp::new("ACTION", Some("waves")), start), None);
+        assert_eq!(responder.reply("d", &Ctcp::new("FINGER", None), start), None);
+        assert!(responder.reply("d", &Ctcp::new("TIME", None), start).is_some());
+        assert!(responder.reply("e", &Ctcp::new("CLIENTINFO", None), start).is_some());
+    }
+
+    #[test]
+    fn one_reply_per_sender_each_interval() {
+        let start = Instant::now();
+        let mut responder = CtcpResponder::new("bot", "src");
+        assert!(responder.reply("alice", &ping(), start).is_some());
+        assert!(responder.reply("alice", &ping(), start + Duration::from_secs(9)).is_none());
+        assert!(responder.reply("bob", &ping(), start + Duration::from_secs(9)).is_some());
+        assert!(responder.reply("alice", &ping(), start + Duration::from_secs(10)).is_some());
+
+        responder.set_min_interval(Duration::from_secs(2));
+        assert!(responder.reply("alice", &ping(), start + Duration::from_secs(11)).is_none());
+        assert!(responder.reply("alice", &ping(), start + Duration::from_secs(12)).is_some());
+    }
+
+    #[test]
+    fn replies_are_limited_across_senders() {
+        let start = Instant::now();
+        let mut responder = CtcpResponder::new("bot", "src");
+        for i in 0..MAX_REPLIES {
+            let nick = format!("nick{}", i);
+            assert!(responder.reply(&nick, &ping(), start + Duration::from_secs(i as u64)).is_some());
+        }
+        assert!(responder.reply("late", &ping(), start + Duration::from_secs(9)).is_none());
+        // the first answer has left the window, so there is room for one more
+        assert!(responder.reply("late", &ping(), start + REPLY_WINDOW).is_some());
+        assert!(responder.reply("later", &ping(), start + REPLY_WINDOW).is_none());
+    }
+
+    #[test]
+    fn senders_are_case_mapped() {
+        let start = Instant::now();
+        let mut responder = CtcpResponder::new("bot", "src");
+        assert!(responder.reply("Dan[m]~", &ping(), start).is_some());
+        assert!(responder.reply("dan{M}^", &ping(), start).is_none());
+
+        responder.set_case_mapping(CaseMapping::StrictRfc1459);
+        let later = start + Duration::from_secs(1);
+        assert!(responder.reply("dan~", &ping(), later).is_some());
+        assert!(responder.reply("DAN~", &ping(), later).is_none());
+        assert!(responder.reply("dan^", &ping(), later).is_some());
+    }
+}
@@ -0,0 +1,175 @@
+use regex::{Regex, RegexBuilder, Error};
+
+use casemap::CaseMapping;
+use format;
+use mask;
+
+// one RPL_LIST line
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ListedChannel {
+    pub name: String,
+    pub users: u32,
+    pub topic: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct ChannelDirectory {
+    channels: Vec<ListedChannel>,
+    // replies between RPL_LISTSTART and RPL_LISTEND
+    #[serde(skip)]
+    syncing: Vec<ListedChannel>,
+    // an unfiltered LIST replaces everything, a filtered one only tells us
+    // about the channels it matched
+    #[serde(skip)]
+    complete: bool,
+    updated_at: Option<u64>,
+}
+
+impl ChannelDirectory {
+    pub fn new() -> ChannelDirectory {
+        ChannelDirectory::default()
+    }
+
+    pub fn set_complete(&mut self, complete: bool) {
+        self.complete = complete;
+    }
+
+    pub fn start(&mut self) {
+        self.syncing.clear();
+    }
+
+    pub fn entry(&mut self, name: &str, users: u32, topic: &str) {
+        self.syncing.push(ListedChannel {
+            name: String::from(name),
+            users,
+            topic: String::from(topic),
+        });
+    }
+
+    // how many channels the listing brought in
+    pub fn end(&mut self, case_mapping: CaseMapping, at: u64) -> usize {
+        let listed: Vec<ListedChannel> = self.syncing.drain(..).collect();
+        let count = listed.len();
+        if self.complete {
+            self.channels = listed;
+        } else {
+            for channel in listed {
+                match self.channels.iter_mut().find(|c| case_mapping.eq(&c.name, &channel.name)) {
+                    Some(existing) => *existing = channel,
+                    None => self.channels.push(channel),
+                }
+            }
+        }
+        self.complete = false;
+        self.updated_at = Some(at);
+        count
+    }
+
+    pub fn channels(&self) -> &[ListedChannel] {
+        &self.channels
+    }
+
+    pub fn updated_at(&self) -> Option<u64> {
+        self.updated_at
+    }
+
+    // busiest first
+    pub fn search(&self, query: &ListQuery, case_mapping: CaseMapping) -> Vec<&ListedChannel> {
+        let mut found: Vec<&ListedChannel> = self.channels.iter()
+            .filter(|c| query.matches(c, case_mapping))
+            .collect();
+        found.sort_by(|a, b| b.users.cmp(&a.users).then_with(|| a.name.cmp(&b.name)));
+        found
+    }
+}
+
+// everything is checked against the directory, the parts ELIST covers are
+// also sent with the LIST so the server does less work
+#[derive(Debug, Clone, Default)]
+pub struct ListQuery {
+    mask: Option<String>,
+    not_mask: Option<String>,
+    min_users: Option<u32>,
+    max_users: Option<u32>,
+    text: Option<String>,
+    regex: Option<Regex>,
+    topic_words: Vec<String>,
+}
+
+impl ListQuery {
+    pub fn new() -> ListQuery {
+        ListQuery::default()
+    }
+
+    // channel names, * and ? work like they do in bans
+    pub fn mask(mut self, mask: &str) -> ListQuery {
+        self.mask = Some(String::from(mask));
+        self
+    }
+
+    pub fn not_mask(mut self, mask: &str) -> ListQuery {
+        self.not_mask = Some(String::from(mask));
+        self
+    }
+
+    pub fn min_users(mut self, users: u32) -> ListQuery {
+        self.min_users = Some(users);
+        self
+    }
+
+    pub fn max_users(mut self, users: u32) -> ListQuery {
+        self.max_users = Some(users);
+        self
+    }
+
+    // in the name or the topic, ignoring case
+    pub fn containing(mut self, text: &str) -> ListQuery {
+        self.text = Some(text.to_lowercase());
+        self
+    }
+
+    // checked against the name, case insensitive
+    pub fn regex(mut self, pattern: &str) -> Result<ListQuery, Error> {
+        self.regex = Some(RegexBuilder::new(pattern).case_insensitive(true).build()?);
+        Ok(self)
+    }
+
+    // every word has to show up in the topic
+    pub fn topic_words(mut self, words: &[&str]) -> ListQuery {
+        self.topic_words = words.iter().map(|w| w.to_lowercase()).collect();
+        self
+    }
+
+    // the LIST parameter for the ELIST letters the network supports, the
+    // U conditions are strict so they are moved by one
+    pub fn elist_params(&self, elist: &str) -> Vec<String> {
+        let elist = elist.to_uppercase();
+        let mut params = Vec::new();
+        if elist.contains('U') {
+            if let Some(min) = self.min_users.filter(|m| *m > 0) {
+                params.push(format!(">{}", min - 1));
+            }
+            if let Some(max) = self.max_users {
+                params.push(format!("<{}", max.saturating_add(1)));
+            }
+        }
+        if elist.contains('M') {
+            params.extend(self.mask.clone());
+        }
+        if elist.contains('N') {
+            params.extend(self.not_mask.as_ref().map(|m| format!("!{}", m)));
+        }
+        params
+    }
+
+    pub fn matches(&self, channel: &ListedChannel, case_mapping: CaseMapping) -> bool {
+        let topic = format::strip(&channel.topic).to_lowercase();
+        self.mask.as_ref().map(|m| mask::matches(m, &channel.name, case_mapping)).unwrap_or(true)
+            && !self.not_mask.as_ref().map(|m| mask::matches(m, &channel.name, case_mapping)).unwrap_or(false)
+            && self.min_users.map(|m| channel.users >= m).unwrap_or(true)
+            && self.max_users.map(|m| channel.users <= m).unwrap_or(true)
+            && self.text.as_ref().map(|t| channel.name.to_lowercase().contains(t.as_str()) || topic.contains(t.as_str())).unwrap_or(true)
+            && self.regex.as_ref().map(|r| r.is_match(&channel.name)).unwrap_or(true)
+            && self.topic_words.iter().all(|w| topic.contains(w.as_str()))
+    }
+}
@@ -0,0 +1,184 @@
+use irc::client::prelude::Response;
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ServerErrorKind {
+    Unknown,
+    NoSuchNick,
+    NoSuchServer,
+    NoSuchChannel,
+    CannotSendToChan,
+    TooManyChannels,
+    WasNoSuchNick,
+    TooManyTargets,
+    NoSuchService,
+    NoOrigin,
+    NoRecipient,
+    NoTextToSend,
+    NoTopLevel,
+    WildTopLevel,
+    BadMask,
+    UnknownCommand,
+    NoMotd,
+    NoAdminInfo,
+    FileError,
+    NoNicknameGiven,
+    ErroneousNickname,
+    NicknameInUse,
+    NickCollision,
+    UnavailableResource,
+    UserNotInChannel,
+    NotOnChannel,
+    UserOnChannel,
+    NoLogin,
+    SummonDisabled,
+    UsersDisabled,
+    NotRegistered,
+    NeedMoreParams,
+    AlreadyRegistered,
+    NoPermForHost,
+    PasswordMismatch,
+    BannedFromServer,
+    WillBeBanned,
+    KeySet,
+    ChannelIsFull,
+    UnknownMode,
+    InviteOnlyChan,
+    BannedFromChan,
+    BadChannelKey,
+    BadChanMask,
+    NoChanModes,
+    BanListFull,
+    NoPrivileges,
+    ChanOpPrivsNeeded,
+    CantKillServer,
+    Restricted,
+    UniqOpPrivsNeeded,
+    NoOperHost,
+    UmodeUnknownFlag,
+    UsersDontMatch,
+    NoPrivs,
+    MonListFull,
+    MetadataLimit,
+    TargetInvalid,
+    NoMatchingKey,
+    KeyInvalid,
+    KeyNotSet,
+    KeyNoPermission,
+    NickLocked,
+    SaslFail,
+    SaslTooLong,
+    SaslAbort,
+    SaslAlready,
+    Other(u16),
+}
+
+impl ServerErrorKind {
+    pub fn from(res: Response) -> Option<ServerErrorKind> {
+        let kind = match res {
+            Response::ERR_UNKNOWNERROR => ServerErrorKind::Unknown,
+            Response::ERR_NOSUCHNICK => ServerErrorKind::NoSuchNick,
+            Response::ERR_NOSUCHSERVER => ServerErrorKind::NoSuchServer,
+            Response::ERR_NOSUCHCHANNEL => ServerErrorKind::NoSuchChannel,
+            Response::ERR_CANNOTSENDTOCHAN => ServerErrorKind::CannotSendToChan,
+            Response::ERR_TOOMANYCHANNELS => ServerErrorKind::TooManyChannels,
+            Response::ERR_WASNOSUCHNICK => ServerErrorKind::WasNoSuchNick,
+            Response::ERR_TOOMANYTARGETS => ServerErrorKind::TooManyTargets,
+            Response::ERR_NOSUCHSERVICE => ServerErrorKind::NoSuchService,
+            Response::ERR_NOORIGIN => ServerErrorKind::NoOrigin,
+            Response::ERR_NORECIPIENT => ServerErrorKind::NoRecipient,
+            Response::ERR_NOTEXTTOSEND => ServerErrorKind::NoTextToSend,
+            Response::ERR_NOTOPLEVEL => ServerErrorKind::NoTopLevel,
+            Response::ERR_WILDTOPLEVEL => ServerErrorKind::WildTopLevel,
+            Response::ERR_BADMASK => ServerErrorKind::BadMask,
+            Response::ERR_UNKNOWNCOMMAND => ServerErrorKind::UnknownCommand,
+            Response::ERR_NOMOTD => ServerErrorKind::NoMotd,
+            Response::ERR_NOADMININFO => ServerErrorKind::NoAdminInfo,
+            Response::ERR_FILEERROR => ServerErrorKind::FileError,
+            Response::ERR_NONICKNAMEGIVEN => ServerErrorKind::NoNicknameGiven,
+            Response::ERR_ERRONEOUSNICKNAME => ServerErrorKind::ErroneousNickname,
+            Response::ERR_NICKNAMEINUSE => ServerErrorKind::NicknameInUse,
+            Response::ERR_NICKCOLLISION => ServerErrorKind::NickCollision,
+            Response::ERR_UNAVAILRESOURCE => ServerErrorKind::UnavailableResource,
+            Response::ERR_USERNOTINCHANNEL => ServerErrorKind::UserNotInChannel,
+            Response::ERR_NOTONCHANNEL => ServerErrorKind::NotOnChannel,
+            Response::ERR_USERONCHANNEL => ServerErrorKind::UserOnChannel,
+            Response::ERR_NOLOGIN => ServerErrorKind::NoLogin,
+            Response::ERR_SUMMONDISABLED => ServerErrorKind::SummonDisabled,
+            Response::ERR_USERSDISABLED => ServerErrorKind::UsersDisabled,
+            Response::ERR_NOTREGISTERED => ServerErrorKind::NotRegistered,
+            Response::ERR_NEEDMOREPARAMS => ServerErrorKind::NeedMoreParams,
+            Response::ERR_ALREADYREGISTRED => ServerErrorKind::AlreadyRegistered,
+            Response::ERR_NOPERMFORHOST => ServerErrorKind::NoPermForHost,
+            Response::ERR_PASSWDMISMATCH => ServerErrorKind::PasswordMismatch,
+            Response::ERR_YOUREBANNEDCREEP => ServerErrorKind::BannedFromServer,
+            Response::ERR_YOUWILLBEBANNED => ServerErrorKind::WillBeBanned,
+            Response::ERR_KEYSET => ServerErrorKind::KeySet,
+            Response::ERR_CHANNELISFULL => ServerErrorKind::ChannelIsFull,
+            Response::ERR_UNKNOWNMODE => ServerErrorKind::UnknownMode,
+            Response::ERR_INVITEONLYCHAN => ServerErrorKind::InviteOnlyChan,
+            Response::ERR_BANNEDFROMCHAN => ServerErrorKind::BannedFromChan,
+            Response::ERR_BADCHANNELKEY => ServerErrorKind::BadChannelKey,
+            Response::ERR_BADCHANMASK => ServerErrorKind::BadChanMask,
+            Response::ERR_NOCHANMODES => ServerErrorKind::NoChanModes,
+            Response::ERR_BANLISTFULL => ServerErrorKind::BanListFull,
+            Response::ERR_NOPRIVILEGES => ServerErrorKind::NoPrivileges,
+            Response::ERR_CHANOPRIVSNEEDED => ServerErrorKind::ChanOpPrivsNeeded,
+            Response::ERR_CANTKILLSERVER => ServerErrorKind::CantKillServer,
+            Response::ERR_RESTRICTED => ServerErrorKind::Restricted,
+            Response::ERR_UNIQOPPRIVSNEEDED => ServerErrorKind::UniqOpPrivsNeeded,
+            Response::ERR_NOOPERHOST => ServerErrorKind::NoOperHost,
+            Response::ERR_UMODEUNKNOWNFLAG => ServerErrorKind::UmodeUnknownFlag,
+            Response::ERR_USERSDONTMATCH => ServerErrorKind::UsersDontMatch,
+            Response::ERR_NOPRIVS => ServerErrorKind::NoPrivs,
+            Response::ERR_MONLISTFULL => ServerErrorKind::MonListFull,
+            Response::ERR_METADATALIMIT => ServerErrorKind::MetadataLimit,
+            Response::ERR_TARGETINVALID => ServerErrorKind::TargetInvalid,
+            Response::ERR_NOMATCHINGKEY => ServerErrorKind::NoMatchingKey,
+            Response::ERR_KEYINVALID => ServerErrorKind::KeyInvalid,
+            Response::ERR_KEYNOTSET => ServerErrorKind::KeyNotSet,
+            Response::ERR_KEYNOPERMISSION => ServerErrorKind::KeyNoPermission,
+            Response::ERR_NICKLOCKED => ServerErrorKind::NickLocked,
+            Response::ERR_SASLFAIL => ServerErrorKind::SaslFail,
+            Response::ERR_SASLTOOLONG => ServerErrorKind::SaslTooLong,
+            Response::ERR_SASLABORT => ServerErrorKind::SaslAbort,
+            Response::ERR_SASLALREADY => ServerErrorKind::SaslAlready,
+            _ => return None,
+        };
+        Some(kind)
+    }
+
+    pub fn from_code(code: &str) -> Option<ServerErrorKind> {
+        match code.parse::<u16>() {
+            Ok(code) if (400..600).contains(&code) => Some(ServerErrorKind::Other(code)),
+            _ => None,
+        }
+    }
+
+    pub fn retryable(self) -> bool {
+        matches!(self, ServerErrorKind::TooManyChannels
+            | ServerErrorKind::TooManyTargets
+            | ServerErrorKind::NicknameInUse
+            | ServerErrorKind::NickCollision
+            | ServerErrorKind::UnavailableResource
+            | ServerErrorKind::ChannelIsFull
+            | ServerErrorKind::NotRegistered
+            | ServerErrorKind::SaslAbort)
+    }
+
+    pub fn is_join_failure(self) -> bool {
+        matches!(self, ServerErrorKind::NoSuchChannel
+            | ServerErrorKind::TooManyChannels
+            | ServerErrorKind::ChannelIsFull
+            | ServerErrorKind::InviteOnlyChan
+            | ServerErrorKind::BannedFromChan
+            | ServerErrorKind::BadChannelKey
+            | ServerErrorKind::BadChanMask
+            | ServerErrorKind::NoChanModes)
+    }
+
+    pub fn is_fatal(self) -> bool {
+        matches!(self, ServerErrorKind::PasswordMismatch
+            | ServerErrorKind::BannedFromServer)
+    }
+}
@@ -1,10 +1,192 @@
-use channel::ChannelMessage;
+use channel::{ChannelMessage, Membership};
+use error::ServerErrorKind;
+use modes::ListEntry;
+use sasl::SaslState;
+use server::ConnectionStatus;
+use who::WhoReply;
+use whois::WhoisInfo;
+
 #[derive(Debug, Serialize, Clone)]
 #[serde(tag = "type", content = "args", rename_all = "kebab-case")]
 pub enum Event {
     Welcome(String),
+    Isupport(Vec<String>),
     Motd(String),
     NewUsers(String, Vec<String>),
     NewMessage(String, ChannelMessage),
+    DirectMessage(String, ChannelMessage),
+    Join {
+        channel: String,
+        nick: String,
+        account: Option<String>,
+        real_name: Option<String>,
+    },
+    Part {
+        channel: String,
+        nick: String,
+        reason: Option<String>,
+    },
+    Kick {
+        channel: String,
+        nick: String,
+        by: String,
+        reason: Option<String>,
+    },
+    MembershipChanged {
+        channel: String,
+        nick: String,
+        state: Membership,
+    },
+    Quit {
+        nick: String,
+        reason: Option<String>,
+    },
+    Netsplit {
+        servers: Vec<String>,
+        nicks: Vec<String>,
+        channels: Vec<String>,
+    },
+    Netjoin {
+        servers: Vec<String>,
+        nicks: Vec<String>,
+        channels: Vec<String>,
+    },
+    Nick {
+        old: String,
+        new: String,
+    },
+    Topic {
+        channel: String,
+        by: Option<String>,
+        old: Option<String>,
+        new: Option<String>,
+        at: Option<u64>,
+    },
+    Invite {
+        channel: String,
+        nick: String,
+        by: String,
+    },
+    Ctcp {
+        from: String,
+        target: String,
+        command: String,
+        params: Option<String>,
+        reply: bool,
+    },
+    Notice {
+        target: String,
+        from: String,
+        text: String,
+    },
+    Mode {
+        target: String,
+        by: String,
+        changes: Vec<String>,
+    },
+    ChannelModes {
+        channel: String,
+        modes: String,
+    },
+    ChannelList {
+        channel: String,
+        mode: char,
+        entries: Vec<ListEntry>,
+    },
+    Away {
+        nick: String,
+        message: Option<String>,
+    },
+    ChgHost {
+        nick: String,
+        user: String,
+        host: String,
+    },
+    Account {
+        nick: String,
+        account: Option<String>,
+    },
+    Who {
+        mask: String,
+        users: Vec<WhoReply>,
+    },
+    Whois(WhoisInfo),
+    ChannelDirectory {
+        channels: usize,
+    },
+    Wallops {
+        from: String,
+        text: String,
+    },
+    Kill {
+        nick: String,
+        by: String,
+        reason: String,
+    },
+    Squit {
+        server: String,
+        by: String,
+        reason: String,
+    },
+    Ping {
+        server: String,
+    },
+    Pong {
+        server: String,
+        token: Option<String>,
+    },
+    // round trip to the server in milliseconds
+    Lag(u64),
+    Status(ConnectionStatus),
+    Cap {
+        sub_command: String,
+        args: Vec<String>,
+    },
+    Capabilities(Vec<String>),
+    Authenticate(String),
+    Sasl {
+        state: SaslState,
+        account: Option<String>,
+    },
+    Batch {
+        reference: String,
+        kind: Option<String>,
+        params: Vec<String>,
+    },
+    ChatHistory {
+        target: Option<String>,
+        messages: Vec<ChannelMessage>,
+    },
+    LabeledResponse {
+        label: Option<String>,
+        lines: usize,
+    },
+    Metadata {
+        target: String,
+        sub_command: Option<String>,
+        params: Vec<String>,
+        value: Option<String>,
+    },
+    Monitor {
+        command: String,
+        targets: Vec<String>,
+    },
+    ServerError {
+        kind: ServerErrorKind,
+        target: Option<String>,
+        message: String,
+        retryable: bool,
+    },
+    Error(String),
+    Disconnected {
+        reason: Option<String>,
+    },
+    Reconnecting {
+        attempt: u32,
+        delay: u64,
+    },
+    Reconnected {
+        channels: Vec<String>,
+    },
     Misc(Option<String>, String, Vec<String>, Option<String>)
-}
\ No newline at end of file
+}
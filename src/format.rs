@@ -0,0 +1,372 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+const BOLD: char = '\u{2}';
+const COLOR: char = '\u{3}';
+const HEX_COLOR: char = '\u{4}';
+const RESET: char = '\u{f}';
+const MONOSPACE: char = '\u{11}';
+const REVERSE: char = '\u{16}';
+const ITALIC: char = '\u{1d}';
+const STRIKETHROUGH: char = '\u{1e}';
+const UNDERLINE: char = '\u{1f}';
+// 99 means the client's own colour
+const DEFAULT_COLOR: u8 = 99;
+
+// what the numbered colours look like, 0 to 15 vary between clients
+const PALETTE: [u32; 99] = [
+    0xFFFFFF, 0x000000, 0x00007F, 0x009300, 0xFF0000, 0x7F0000, 0x9C009C, 0xFC7F00,
+    0xFFFF00, 0x00FC00, 0x009393, 0x00FFFF, 0x0000FC, 0xFF00FF, 0x7F7F7F, 0xD2D2D2,
+    0x470000, 0x472100, 0x474700, 0x324700, 0x004700, 0x00472C, 0x004747, 0x002747, 0x000047, 0x2E0047, 0x470047, 0x47002A,
+    0x740000, 0x743A00, 0x747400, 0x517400, 0x007400, 0x007449, 0x007474, 0x004074, 0x000074, 0x4B0074, 0x740074, 0x740045,
+    0xB50000, 0xB56300, 0xB5B500, 0x7DB500, 0x00B500, 0x00B571, 0x00B5B5, 0x0063B5, 0x0000B5, 0x7500B5, 0xB500B5, 0xB5006B,
+    0xFF0000, 0xFF8C00, 0xFFFF00, 0xB2FF00, 0x00FF00, 0x00FFA0, 0x00FFFF, 0x008CFF, 0x0000FF, 0xA500FF, 0xFF00FF, 0xFF0098,
+    0xFF5959, 0xFFB459, 0xFFFF71, 0xCFFF60, 0x6FFF6F, 0x65FFC9, 0x6DFFFF, 0x59B4FF, 0x5959FF, 0xC459FF, 0xFF66FF, 0xFF59BC,
+    0xFF9C9C, 0xFFD39C, 0xFFFF9C, 0xE2FF9C, 0x9CFF9C, 0x9CFFDB, 0x9CFFFF, 0x9CD3FF, 0x9C9CFF, 0xDC9CFF, 0xFF9CFF, 0xFF94D3,
+    0x000000, 0x131313, 0x282828, 0x363636, 0x4D4D4D, 0x656565, 0x818181, 0x9F9F9F, 0xBCBCBC, 0xE2E2E2, 0xFFFFFF,
+];
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+pub enum Color {
+    Irc(u8),
+    Rgb(u8, u8, u8),
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, PartialEq, Eq)]
+pub struct Style {
+    pub bold: bool,
+    pub italic: bool,
+    pub underline: bool,
+    pub strikethrough: bool,
+    pub monospace: bool,
+    pub reverse: bool,
+    pub fg: Option<Color>,
+    pub bg: Option<Color>,
+}
+
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Span {
+    pub text: String,
+    pub style: Style,
+}
+
+impl Span {
+    pub fn plain(text: &str) -> Span {
+        Span::styled(text, Style::default())
+    }
+
+    pub fn styled(text: &str, style: Style) -> Span {
+        Span {
+            text: String::from(text),
+            style,
+        }
+    }
+}
+
+impl Style {
+    // true if moving from self to other only ever switches things on,
+    // anything being switched off needs a reset first
+    fn only_adds(&self, other: &Style) -> bool {
+        (!self.bold || other.bold)
+            && (!self.italic || other.italic)
+            && (!self.underline || other.underline)
+            && (!self.strikethrough || other.strikethrough)
+            && (!self.monospace || other.monospace)
+            && (!self.reverse || other.reverse)
+            && (self.fg.is_none() || other.fg.is_some())
+            && (self.bg.is_none() || other.bg.is_some())
+    }
+}
+
+pub fn parse(text: &str) -> Vec<Span> {
+    let mut spans = Vec::new();
+    let mut style = Style::default();
+    let mut current = String::new();
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let mut next = style;
+        match c {
+            BOLD => next.bold = !next.bold,
+            ITALIC => next.italic = !next.italic,
+            UNDERLINE => next.underline = !next.underline,
+            STRIKETHROUGH => next.strikethrough = !next.strikethrough,
+            MONOSPACE => next.monospace = !next.monospace,
+            REVERSE => next.reverse = !next.reverse,
+            RESET => next = Style::default(),
+            COLOR => {
+                let (fg, bg) = read_color(&mut chars, read_irc_color);
+                next.fg = fg.filter(|c| *c != Color::Irc(DEFAULT_COLOR));
+                next.bg = if fg.is_some() && bg.is_none() { next.bg } else { bg.filter(|c| *c != Color::Irc(DEFAULT_COLOR)) };
+            },
+            HEX_COLOR => {
+                let (fg, bg) = read_color(&mut chars, read_hex_color);
+                next.fg = fg;
+                next.bg = if fg.is_some() && bg.is_none() { next.bg } else { bg };
+            },
+            _ => {
+                current.push(c);
+                continue;
+            },
+        }
+        if next != style {
+            push_span(&mut spans, &current, style);
+            current.clear();
+            style = next;
+        }
+    }
+    push_span(&mut spans, &current, style);
+    spans
+}
+
+// codes that are switched straight back off shouldn't split the text
+fn push_span(spans: &mut Vec<Span>, text: &str, style: Style) {
+    if text.is_empty() {
+        return;
+    }
+    match spans.last_mut() {
+        Some(last) if last.style == style => last.text.push_str(text),
+        _ => spans.push(Span::styled(text, style)),
+    }
+}
+
+pub fn strip(text: &str) -> String {
+    parse(text).into_iter().map(|s| s.text).collect()
+}
+
+// parse gives back the same spans except for colours a code can't hold as
+// they are: a hex background with no foreground comes back as the nearest
+// numbered colour, and a numbered colour next to a hex one comes back as hex
+pub fn render(spans: &[Span]) -> String {
+    let mut ret = String::new();
+    let mut prev = Style::default();
+    for span in spans {
+        let style = span.style;
+        if !prev.only_adds(&style) {
+            ret.push(RESET);
+            prev = Style::default();
+        }
+        if style.bold && !prev.bold {
+            ret.push(BOLD);
+        }
+        if style.italic && !prev.italic {
+            ret.push(ITALIC);
+        }
+        if style.underline && !prev.underline {
+            ret.push(UNDERLINE);
+        }
+        if style.strikethrough && !prev.strikethrough {
+            ret.push(STRIKETHROUGH);
+        }
+        if style.monospace && !prev.monospace {
+            ret.push(MONOSPACE);
+        }
+        if style.reverse && !prev.reverse {
+            ret.push(REVERSE);
+        }
+        if style.fg != prev.fg || style.bg != prev.bg {
+            let code = color_code(style.fg, style.bg);
+            ret.push_str(&code);
+            // an empty bold toggle so ",RRGGBB" isn't taken as a background
+            if !code.is_empty() && !code.contains(',') && span.text.starts_with(',') {
+                ret.push(BOLD);
+                ret.push(BOLD);
+            }
+        }
+        ret.push_str(&span.text);
+        prev = style;
+    }
+    ret
+}
+
+fn color_code(fg: Option<Color>, bg: Option<Color>) -> String {
+    // both colours are written, two digits each, so text starting with a
+    // comma or a digit can't be read as part of the code
+    match (fg, bg) {
+        (None, None) => String::new(),
+        (Some(Color::Rgb(..)), _) | (_, Some(Color::Rgb(..))) => match (fg.and_then(rgb), bg.and_then(rgb)) {
+            (Some(f), Some(b)) => format!("{}{},{}", HEX_COLOR, hex(f), hex(b)),
+            // hex codes have no way to say the default foreground
+            (None, Some(b)) => format!("{}{:02},{:02}", COLOR, DEFAULT_COLOR, nearest(b)),
+            // nor the default background, render keeps a following comma apart
+            (Some(f), None) => format!("{}{}", HEX_COLOR, hex(f)),
+            (None, None) => String::new(),
+        },
+        (fg, bg) => format!("{}{:02},{:02}", COLOR, irc(fg), irc(bg)),
+    }
+}
+
+fn irc(color: Option<Color>) -> u8 {
+    match color {
+        Some(Color::Irc(c)) => c,
+        _ => DEFAULT_COLOR,
+    }
+}
+
+fn rgb(color: Color) -> Option<(u8, u8, u8)> {
+    match color {
+        Color::Rgb(r, g, b) => Some((r, g, b)),
+        Color::Irc(c) => PALETTE.get(c as usize).map(|c| ((c >> 16) as u8, (c >> 8) as u8, *c as u8)),
+    }
+}
+
+fn hex((r, g, b): (u8, u8, u8)) -> String {
+    format!("{:02X}{:02X}{:02X}", r, g, b)
+}
+
+// the numbered colour closest to an rgb one
+fn nearest((r, g, b): (u8, u8, u8)) -> u8 {
+    let distance = |i: usize| {
+        let (pr, pg, pb) = rgb(Color::Irc(i as u8)).unwrap_or((0, 0, 0));
+        let d = |a: u8, b: u8| (i32::from(a) - i32::from(b)).pow(2);
+        d(r, pr) + d(g, pg) + d(b, pb)
+    };
+    (0..PALETTE.len()).min_by_key(|i| distance(*i)).unwrap_or(0) as u8
+}
+
+fn read_color<F>(chars: &mut Peekable<Chars>, read: F) -> (Option<Color>, Option<Color>)
+where F: Fn(&mut Peekable<Chars>) -> Option<Color> {
+    let fg = match read(chars) {
+        Some(fg) => fg,
+        None => return (None, None),
+    };
+    // only eat the comma if a colour follows it
+    let mut look_ahead = chars.clone();
+    if look_ahead.next() == Some(',') && read(&mut look_ahead).is_some() {
+        chars.next();
+        return (Some(fg), read(chars));
+    }
+    (Some(fg), None)
+}
+
+fn read_irc_color(chars: &mut Peekable<Chars>) -> Option<Color> {
+    let mut value: Option<u8> = None;
+    for _ in 0..2 {
+        match chars.peek().and_then(|c| c.to_digit(10)) {
+            Some(d) => {
+                chars.next();
+                value = Some(value.unwrap_or(0) * 10 + d as u8);
+            },
+            None => break,
+        }
+    }
+    value.map(Color::Irc)
+}
+
+fn read_hex_color(chars: &mut Peekable<Chars>) -> Option<Color> {
+    let digits: String = chars.clone().take(6).collect();
+    if digits.len() != 6 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    for _ in 0..6 {
+        chars.next();
+    }
+    let channel = |i: usize| u8::from_str_radix(&digits[i..i + 2], 16).unwrap_or(0);
+    Some(Color::Rgb(channel(0), channel(2), channel(4)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn style(fg: Option<Color>, bg: Option<Color>) -> Style {
+        Style {
+            fg,
+            bg,
+            ..Style::default()
+        }
+    }
+
+    fn round_trip(spans: Vec<Span>) {
+        let rendered = render(&spans);
+        assert_eq!(parse(&rendered), spans, "{:?}", rendered);
+    }
+
+    #[test]
+    fn plain_text() {
+        assert_eq!(parse("hello"), vec![Span::plain("hello")]);
+        assert!(parse("").is_empty());
+        round_trip(vec![Span::plain("hello")]);
+    }
+
+    #[test]
+    fn toggles() {
+        let bold = Style { bold: true, ..Style::default() };
+        let bold_italic = Style { italic: true, ..bold };
+        assert_eq!(parse("a\u{2}b\u{1d}c\u{2}d\u{f}e"), vec![
+            Span::plain("a"),
+            Span::styled("b", bold),
+            Span::styled("c", bold_italic),
+            Span::styled("d", Style { italic: true, ..Style::default() }),
+            Span::plain("e"),
+        ]);
+        // toggled on and straight back off changes nothing
+        assert_eq!(parse("a\u{2}\u{2}b"), vec![Span::plain("ab")]);
+        let all = Style {
+            bold: true,
+            italic: true,
+            underline: true,
+            strikethrough: true,
+            monospace: true,
+            reverse: true,
+            ..Style::default()
+        };
+        round_trip(vec![Span::styled("all", all), Span::styled("some", bold), Span::plain("none"), Span::styled("again", bold_italic)]);
+    }
+
+    #[test]
+    fn color_codes() {
+        assert_eq!(parse("\u{3}4,12x"), vec![Span::styled("x", style(Some(Color::Irc(4)), Some(Color::Irc(12))))]);
+        assert_eq!(parse("\u{3}04x\u{3}y"), vec![
+            Span::styled("x", style(Some(Color::Irc(4)), None)),
+            Span::plain("y"),
+        ]);
+        // a foreground on its own keeps the background
+        assert_eq!(parse("\u{3}4,2x\u{3}5y"), vec![
+            Span::styled("x", style(Some(Color::Irc(4)), Some(Color::Irc(2)))),
+            Span::styled("y", style(Some(Color::Irc(5)), Some(Color::Irc(2)))),
+        ]);
+        // the comma is only part of the code when a colour follows it
+        assert_eq!(parse("\u{3}4,x"), vec![Span::styled(",x", style(Some(Color::Irc(4)), None))]);
+        assert_eq!(parse("\u{3}99,99x"), vec![Span::plain("x")]);
+        assert_eq!(parse("\u{4}FF8000,000000x"), vec![
+            Span::styled("x", style(Some(Color::Rgb(255, 128, 0)), Some(Color::Rgb(0, 0, 0)))),
+        ]);
+        assert_eq!(parse("\u{4}FF80x"), vec![Span::plain("FF80x")]);
+    }
+
+    #[test]
+    fn colors_round_trip() {
+        let irc = Some(Color::Irc(4));
+        let irc_bg = Some(Color::Irc(12));
+        let rgb = Some(Color::Rgb(1, 2, 3));
+        let rgb_bg = Some(Color::Rgb(200, 100, 50));
+        for &(fg, bg) in &[(irc, None), (None, irc_bg), (irc, irc_bg), (rgb, None), (rgb, rgb_bg)] {
+            round_trip(vec![Span::plain("a"), Span::styled("b", style(fg, bg)), Span::plain("c")]);
+            // text that looks like more of the code
+            round_trip(vec![Span::styled(",12 9", style(fg, bg)), Span::plain(",ABCDEF")]);
+            round_trip(vec![Span::styled(",ABCDEF", style(fg, bg))]);
+        }
+        // from one colour straight to another
+        round_trip(vec![Span::styled("a", style(irc, irc_bg)), Span::styled("b", style(rgb, rgb_bg)), Span::styled("c", style(irc, None))]);
+    }
+
+    #[test]
+    fn lossy_colors() {
+        // hex has no default foreground, the background becomes a numbered one
+        let spans = parse(&render(&[Span::styled("x", style(None, Some(Color::Rgb(0, 0, 250))))]));
+        assert_eq!(spans, vec![Span::styled("x", style(None, Some(Color::Irc(12))))]);
+        // a numbered colour next to a hex one is written as hex
+        let spans = parse(&render(&[Span::styled("x", style(Some(Color::Irc(3)), Some(Color::Rgb(1, 2, 3))))]));
+        assert_eq!(spans, vec![Span::styled("x", style(Some(Color::Rgb(0, 0x93, 0)), Some(Color::Rgb(1, 2, 3))))]);
+        let spans = parse(&render(&[Span::styled("x", style(Some(Color::Rgb(1, 2, 3)), Some(Color::Irc(2))))]));
+        assert_eq!(spans, vec![Span::styled("x", style(Some(Color::Rgb(1, 2, 3)), Some(Color::Rgb(0, 0, 0x7F))))]);
+    }
+
+    #[test]
+    fn strip_codes() {
+        assert_eq!(strip("\u{2}bold\u{2} \u{3}4,12red\u{3} \u{4}FF0000hex\u{f} done"), "bold red hex done");
+        assert_eq!(strip("\u{3}12,34"), "");
+        assert_eq!(strip("\u{3}4,x"), ",x");
+        assert_eq!(strip("no codes"), "no codes");
+    }
+}
@@ -0,0 +1,116 @@
+use std::fmt::{Display, Formatter, Result};
+
+use casemap::CaseMapping;
+use mask;
+
+// nick!user@host, user and host are missing when the server only sent a nick
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct Hostmask {
+    pub nick: String,
+    pub user: Option<String>,
+    pub host: Option<String>,
+}
+
+// who a message came from
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Source {
+    User(Hostmask),
+    Server(String),
+}
+
+impl Hostmask {
+    pub fn new(nick: &str, user: Option<&str>, host: Option<&str>) -> Hostmask {
+        Hostmask {
+            nick: String::from(nick),
+            user: user.map(String::from),
+            host: host.map(String::from),
+        }
+    }
+
+    pub fn parse(source: &str) -> Hostmask {
+        let mut at = source.splitn(2, '@');
+        let nick_user = at.next().unwrap_or("");
+        let host = at.next();
+        let mut bang = nick_user.splitn(2, '!');
+        let nick = bang.next().unwrap_or("");
+        Hostmask::new(nick, bang.next(), host)
+    }
+
+    pub fn nick(&self) -> &str {
+        &self.nick
+    }
+
+    pub fn user(&self) -> Option<&str> {
+        self.user.as_deref()
+    }
+
+    pub fn host(&self) -> Option<&str> {
+        self.host.as_deref()
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.user.is_some() && self.host.is_some()
+    }
+
+    // a missing user or host is treated as * so a bare nick can still be
+    // checked against bans
+    pub fn matches(&self, mask: &str, case_mapping: CaseMapping) -> bool {
+        let full = format!("{}!{}@{}", self.nick, self.user().unwrap_or("*"), self.host().unwrap_or("*"));
+        mask::matches(mask, &full, case_mapping)
+    }
+}
+
+impl Display for Hostmask {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        write!(f, "{}", self.nick)?;
+        if let Some(ref user) = self.user {
+            write!(f, "!{}", user)?;
+        }
+        if let Some(ref host) = self.host {
+            write!(f, "@{}", host)?;
+        }
+        Ok(())
+    }
+}
+
+impl Source {
+    // nicks can't have a '.' in them, server names always do
+    pub fn parse(prefix: &str) -> Source {
+        if !prefix.contains('!') && !prefix.contains('@') && prefix.contains('.') {
+            Source::Server(String::from(prefix))
+        } else {
+            Source::User(Hostmask::parse(prefix))
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        match *self {
+            Source::User(ref mask) => &mask.nick,
+            Source::Server(ref name) => name,
+        }
+    }
+
+    pub fn hostmask(&self) -> Option<&Hostmask> {
+        match *self {
+            Source::User(ref mask) => Some(mask),
+            Source::Server(_) => None,
+        }
+    }
+
+    pub fn is_server(&self) -> bool {
+        match *self {
+            Source::Server(_) => true,
+            Source::User(_) => false,
+        }
+    }
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        match *self {
+            Source::User(ref mask) => mask.fmt(f),
+            Source::Server(ref name) => write!(f, "{}", name),
+        }
+    }
+}
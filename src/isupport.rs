@@ -0,0 +1,487 @@
+use std::collections::BTreeMap;
+
+use irc::proto::{ChannelMode, Mode};
+
+// what most networks use when they don't say otherwise
+const DEFAULT_CHANTYPES: &str = "#&";
+const DEFAULT_PREFIX: &str = "(qaohv)~&@%+";
+const DEFAULT_CHANMODES: &str = "beI,k,l,imnpst";
+const DEFAULT_CASEMAPPING: &str = "rfc1459";
+const DEFAULT_MODES: usize = 3;
+const DEFAULT_LINELEN: usize = 512;
+const DEFAULT_EXTBAN_PREFIX: char = '$';
+// used to guess how long the prefix the server adds to our messages is
+const DEFAULT_NICKLEN: usize = 30;
+const DEFAULT_USERLEN: usize = 10;
+const DEFAULT_HOSTLEN: usize = 63;
+
+// the four CHANMODES groups plus the modes from PREFIX
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum ModeKind {
+    // A: lists like bans, always take an argument
+    List,
+    // B: always take an argument, e.g. the key
+    AlwaysArg,
+    // C: only take an argument when set, e.g. the limit
+    SetArg,
+    // D: plain flags
+    Flag,
+    // given to a user in the channel, e.g. op or voice
+    Prefix,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NetworkInfo {
+    tokens: BTreeMap<String, Option<String>>,
+    network: Option<String>,
+    chan_types: String,
+    prefixes: Vec<(char, char)>,
+    chan_modes: Vec<String>,
+    case_mapping: String,
+    nick_len: Option<usize>,
+    topic_len: Option<usize>,
+    max_modes: Option<usize>,
+    targ_max: BTreeMap<String, Option<usize>>,
+    line_len: usize,
+}
+
+impl Default for NetworkInfo {
+    fn default() -> Self {
+        NetworkInfo::new()
+    }
+}
+
+impl NetworkInfo {
+    pub fn new() -> NetworkInfo {
+        let mut ret = NetworkInfo {
+            tokens: BTreeMap::new(),
+            network: None,
+            chan_types: String::new(),
+            prefixes: Vec::new(),
+            chan_modes: Vec::new(),
+            case_mapping: String::new(),
+            nick_len: None,
+            topic_len: None,
+            max_modes: None,
+            targ_max: BTreeMap::new(),
+            line_len: DEFAULT_LINELEN,
+        };
+        ret.refresh();
+        ret
+    }
+
+    // tokens from a single RPL_ISUPPORT, without our nick or the trailing text
+    pub fn apply(&mut self, tokens: &[String]) {
+        for token in tokens {
+            if let Some(removed) = token.strip_prefix('-') {
+                self.tokens.remove(&removed.to_uppercase());
+                continue;
+            }
+            let mut parts = token.splitn(2, '=');
+            let key = parts.next().unwrap_or("").to_uppercase();
+            if key.is_empty() {
+                continue;
+            }
+            let value = parts.next().map(unescape).filter(|v| !v.is_empty());
+            self.tokens.insert(key, value);
+        }
+        self.refresh();
+    }
+
+    // everything below is worked out again from the raw tokens so a -TOKEN
+    // puts things back to the default
+    fn refresh(&mut self) {
+        self.network = self.get("NETWORK").map(String::from);
+        self.chan_types = String::from(match self.tokens.get("CHANTYPES") {
+            // CHANTYPES= with nothing after it means there are no channels at all
+            Some(value) => value.as_deref().unwrap_or(""),
+            None => DEFAULT_CHANTYPES,
+        });
+        self.prefixes = parse_prefix(match self.tokens.get("PREFIX") {
+            Some(value) => value.as_deref().unwrap_or(""),
+            None => DEFAULT_PREFIX,
+        });
+        let mut chan_modes: Vec<String> = self.get("CHANMODES")
+            .unwrap_or(DEFAULT_CHANMODES)
+            .split(',')
+            .map(String::from)
+            .collect();
+        chan_modes.resize(4, String::new());
+        self.chan_modes = chan_modes;
+        self.case_mapping = String::from(self.get("CASEMAPPING").unwrap_or(DEFAULT_CASEMAPPING));
+        self.nick_len = self.number("NICKLEN");
+        self.topic_len = self.number("TOPICLEN");
+        self.max_modes = match self.tokens.get("MODES") {
+            // MODES on its own means there is no limit
+            Some(None) => None,
+            Some(Some(value)) => value.parse().ok(),
+            None => Some(DEFAULT_MODES),
+        };
+        self.targ_max = self.get("TARGMAX").unwrap_or("")
+            .split(',')
+            .filter(|t| !t.is_empty())
+            .map(|t| {
+                let mut parts = t.splitn(2, ':');
+                let command = parts.next().unwrap_or("").to_uppercase();
+                (command, parts.next().and_then(|n| n.parse().ok()))
+            })
+            .collect();
+        self.line_len = self.number("LINELEN").unwrap_or(DEFAULT_LINELEN);
+    }
+
+    pub fn get(&self, token: &str) -> Option<&str> {
+        self.tokens.get(token).and_then(|v| v.as_deref())
+    }
+
+    pub fn contains(&self, token: &str) -> bool {
+        self.tokens.contains_key(token)
+    }
+
+    fn number(&self, token: &str) -> Option<usize> {
+        self.get(token).and_then(|v| v.parse().ok())
+    }
+
+    pub fn network(&self) -> Option<&str> {
+        self.network.as_deref()
+    }
+
+    pub fn is_channel(&self, name: &str) -> bool {
+        match name.chars().next() {
+            Some(c) => self.chan_types.contains(c),
+            None => false,
+        }
+    }
+
+    // STATUSMSG=@+ lets "@#rust" reach only the ops of #rust, this is the
+    // channel such a message belongs to
+    pub fn status_target<'a>(&self, target: &'a str) -> &'a str {
+        let status = self.get("STATUSMSG").unwrap_or("");
+        let channel = target.trim_start_matches(|c| status.contains(c));
+        if self.is_channel(channel) {
+            channel
+        } else {
+            target
+        }
+    }
+
+    // (mode, symbol) pairs, highest rank first
+    pub fn prefixes(&self) -> &[(char, char)] {
+        &self.prefixes
+    }
+
+    pub fn prefix_mode(&self, symbol: char) -> Option<char> {
+        self.prefixes.iter().find(|p| p.1 == symbol).map(|p| p.0)
+    }
+
+    pub fn prefix_symbol(&self, mode: char) -> Option<char> {
+        self.prefixes.iter().find(|p| p.0 == mode).map(|p| p.1)
+    }
+
+    pub fn is_prefix_mode(&self, mode: char) -> bool {
+        self.prefix_symbol(mode).is_some()
+    }
+
+    pub fn mode_kind(&self, mode: char) -> Option<ModeKind> {
+        if self.is_prefix_mode(mode) {
+            return Some(ModeKind::Prefix);
+        }
+        let kinds = [ModeKind::List, ModeKind::AlwaysArg, ModeKind::SetArg, ModeKind::Flag];
+        self.chan_modes.iter()
+            .zip(kinds.iter())
+            .find(|&(modes, _)| modes.contains(mode))
+            .map(|(_, kind)| *kind)
+    }
+
+    // where the mode sits in its CHANMODES group, for putting modes in a stable order
+    pub fn mode_position(&self, mode: char) -> usize {
+        self.chan_modes.iter()
+            .find_map(|modes| modes.chars().position(|m| m == mode))
+            .unwrap_or(usize::MAX)
+    }
+
+    pub fn takes_arg(&self, mode: char, adding: bool) -> bool {
+        match self.mode_kind(mode) {
+            Some(ModeKind::List) | Some(ModeKind::AlwaysArg) | Some(ModeKind::Prefix) => true,
+            Some(ModeKind::SetArg) => adding,
+            Some(ModeKind::Flag) | None => false,
+        }
+    }
+
+    // EXTBAN=<prefix>,<types>, an empty prefix means extbans are written
+    // without one
+    pub fn extban_prefix(&self) -> Option<char> {
+        match self.get("EXTBAN") {
+            Some(value) => value.split(',').next().and_then(|p| p.chars().next()),
+            None => Some(DEFAULT_EXTBAN_PREFIX),
+        }
+    }
+
+    pub fn case_mapping(&self) -> &str {
+        &self.case_mapping
+    }
+
+    pub fn nick_len(&self) -> Option<usize> {
+        self.nick_len
+    }
+
+    pub fn topic_len(&self) -> Option<usize> {
+        self.topic_len
+    }
+
+    // how many modes with an argument can go in one MODE, None for no limit
+    pub fn max_modes(&self) -> Option<usize> {
+        self.max_modes
+    }
+
+    pub fn max_targets(&self, command: &str) -> Option<usize> {
+        self.targ_max.get(&command.to_uppercase()).cloned().unwrap_or(None)
+    }
+
+    // the most text that fits in one `<command> <target> :<text>` once the
+    // server has put our full hostmask in front of it
+    pub fn max_text_len(&self, command: &str, target: &str) -> usize {
+        let source = 1 + self.nick_len.unwrap_or(DEFAULT_NICKLEN)
+            + 1 + self.number("USERLEN").unwrap_or(DEFAULT_USERLEN)
+            + 1 + self.number("HOSTLEN").unwrap_or(DEFAULT_HOSTLEN)
+            + 1;
+        let overhead = source + command.len() + 1 + target.len() + 2 + 2;
+        self.line_len.saturating_sub(overhead).max(1)
+    }
+
+    // break text into pieces that each fit in a single message, splitting
+    // on spaces where possible and never inside a character
+    pub fn split_text(&self, command: &str, target: &str, text: &str) -> Vec<String> {
+        let max = self.max_text_len(command, target);
+        let mut ret = Vec::new();
+        let mut rest = text;
+        while rest.len() > max {
+            let mut end = max;
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            // a limit smaller than one character still has to make progress
+            if end == 0 {
+                end = rest.chars().next().map(char::len_utf8).unwrap_or(rest.len());
+            }
+            let split = match rest[..end].rfind(' ') {
+                Some(space) if space > 0 => space,
+                _ => end,
+            };
+            ret.push(String::from(&rest[..split]));
+            rest = rest[split..].trim_start_matches(' ');
+        }
+        if !rest.is_empty() || ret.is_empty() {
+            ret.push(String::from(rest));
+        }
+        ret
+    }
+
+    pub fn parse_modes(&self, modes: &str, args: &[String]) -> Vec<Mode<ChannelMode>> {
+        let mut args = args.iter();
+        let mut adding = true;
+        let mut ret = Vec::new();
+        for c in modes.chars() {
+            match c {
+                '+' => adding = true,
+                '-' => adding = false,
+                _ => {
+                    let arg = if self.takes_arg(c, adding) {
+                        args.next().cloned()
+                    } else {
+                        None
+                    };
+                    ret.push(if adding {
+                        Mode::Plus(channel_mode(c), arg)
+                    } else {
+                        Mode::Minus(channel_mode(c), arg)
+                    });
+                },
+            }
+        }
+        ret
+    }
+
+    // the irc crate splits up mode arguments with its own fixed idea of which
+    // modes take one, put them back in order and split them again properly.
+    // Arguments it didn't think belonged to any mode are already gone.
+    pub fn reparse_modes(&self, modes: &[Mode<ChannelMode>]) -> Vec<Mode<ChannelMode>> {
+        let mut flags = String::new();
+        let mut args = Vec::new();
+        let mut term_sign = None;
+        for mode in modes {
+            let (sign, mode, arg) = match *mode {
+                Mode::Plus(ref mode, ref arg) => ('+', mode, arg),
+                Mode::Minus(ref mode, ref arg) => ('-', mode, arg),
+            };
+            if term_sign != Some(sign) {
+                flags.push(sign);
+                term_sign = Some(sign);
+            }
+            // a sign in the middle of "+b-l" comes through as an unknown
+            // mode, written back out it switches the sign again
+            flags.push(mode_char(mode));
+            if let Some(ref arg) = *arg {
+                args.push(arg.clone());
+            }
+        }
+        self.parse_modes(&flags, &args)
+    }
+}
+
+pub fn mode_char(mode: &ChannelMode) -> char {
+    mode.to_string().chars().next().unwrap_or('?')
+}
+
+pub fn channel_mode(c: char) -> ChannelMode {
+    match c {
+        'b' => ChannelMode::Ban,
+        'e' => ChannelMode::Exception,
+        'l' => ChannelMode::Limit,
+        'i' => ChannelMode::InviteOnly,
+        'I' => ChannelMode::InviteException,
+        'k' => ChannelMode::Key,
+        'm' => ChannelMode::Moderated,
+        'r' => ChannelMode::RegisteredOnly,
+        's' => ChannelMode::Secret,
+        't' => ChannelMode::ProtectedTopic,
+        'n' => ChannelMode::NoExternalMessages,
+        'q' => ChannelMode::Founder,
+        'a' => ChannelMode::Admin,
+        'o' => ChannelMode::Oper,
+        'h' => ChannelMode::Halfop,
+        'v' => ChannelMode::Voice,
+        _ => ChannelMode::Unknown(c),
+    }
+}
+
+fn parse_prefix(value: &str) -> Vec<(char, char)> {
+    // (modes)symbols, PREFIX= on its own means nobody gets a prefix
+    let value = match value.strip_prefix('(') {
+        Some(value) => value,
+        None => return Vec::new(),
+    };
+    let mut parts = value.splitn(2, ')');
+    let modes = parts.next().unwrap_or("");
+    let symbols = parts.next().unwrap_or("");
+    modes.chars().zip(symbols.chars()).collect()
+}
+
+// values may contain \xHH escapes for bytes that can't be sent as they are
+pub fn unescape(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut ret = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'\\' && i + 4 <= bytes.len() && bytes[i + 1] == b'x' {
+            let hex = ::std::str::from_utf8(&bytes[i + 2..i + 4]).ok();
+            if let Some(b) = hex.and_then(|h| u8::from_str_radix(h, 16).ok()) {
+                ret.push(b);
+                i += 4;
+                continue;
+            }
+        }
+        ret.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&ret).into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn network(tokens: &[&str]) -> NetworkInfo {
+        let mut network = NetworkInfo::new();
+        network.apply(&tokens.iter().map(|t| String::from(*t)).collect::<Vec<_>>());
+        network
+    }
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| String::from(*a)).collect()
+    }
+
+    #[test]
+    fn split_text_short() {
+        let network = NetworkInfo::new();
+        assert_eq!(network.split_text("PRIVMSG", "#rust", "hello"), vec!["hello"]);
+        assert_eq!(network.split_text("PRIVMSG", "#rust", ""), vec![""]);
+    }
+
+    #[test]
+    fn split_text_on_spaces() {
+        let network = network(&["LINELEN=150"]);
+        let max = network.max_text_len("PRIVMSG", "#rust");
+        let text = vec!["word"; 40].join(" ");
+        let parts = network.split_text("PRIVMSG", "#rust", &text);
+        assert!(parts.len() > 1);
+        assert!(parts.iter().all(|p| p.len() <= max && !p.starts_with(' ') && !p.ends_with(' ')));
+        assert_eq!(parts.join(" "), text);
+    }
+
+    #[test]
+    fn split_text_on_char_boundaries() {
+        let network = network(&["LINELEN=150"]);
+        let text = "é".repeat(100);
+        let parts = network.split_text("PRIVMSG", "#rust", &text);
+        assert!(parts.len() > 1);
+        assert_eq!(parts.concat(), text);
+    }
+
+    #[test]
+    fn split_text_tiny_limit() {
+        // nothing fits, each character still goes out on its own
+        let network = network(&["LINELEN=10"]);
+        assert_eq!(network.split_text("PRIVMSG", "#rust", "aé b"), vec!["a", "é", "b"]);
+    }
+
+    #[test]
+    fn empty_prefix() {
+        assert_eq!(NetworkInfo::new().prefixes().len(), 5);
+        let empty = network(&["PREFIX="]);
+        assert!(empty.prefixes().is_empty());
+        assert!(!empty.is_prefix_mode('o'));
+        let short = network(&["PREFIX=(ov)@+"]);
+        assert_eq!(short.prefixes(), &[('o', '@'), ('v', '+')]);
+    }
+
+    #[test]
+    fn parse_modes_by_kind() {
+        let network = network(&["CHANMODES=beI,kf,l,imnpst"]);
+        let modes = network.parse_modes("+kfl-lb+o", &args(&["key", "#fwd", "10", "*!*@bad", "nick"]));
+        assert_eq!(modes, vec![
+            Mode::Plus(ChannelMode::Key, Some(String::from("key"))),
+            Mode::Plus(channel_mode('f'), Some(String::from("#fwd"))),
+            Mode::Plus(ChannelMode::Limit, Some(String::from("10"))),
+            Mode::Minus(ChannelMode::Limit, None),
+            Mode::Minus(ChannelMode::Ban, Some(String::from("*!*@bad"))),
+            Mode::Plus(ChannelMode::Oper, Some(String::from("nick"))),
+        ]);
+    }
+
+    #[test]
+    fn reparse_modes_moves_arguments() {
+        // the irc crate thinks +f takes nothing so gives its argument to +n
+        let network = network(&["CHANMODES=beI,kf,l,imnpst"]);
+        let parsed = vec![
+            Mode::Plus(channel_mode('f'), None),
+            Mode::Plus(ChannelMode::NoExternalMessages, Some(String::from("#fwd"))),
+            Mode::Minus(ChannelMode::Limit, None),
+        ];
+        assert_eq!(network.reparse_modes(&parsed), vec![
+            Mode::Plus(channel_mode('f'), Some(String::from("#fwd"))),
+            Mode::Plus(ChannelMode::NoExternalMessages, None),
+            Mode::Minus(ChannelMode::Limit, None),
+        ]);
+    }
+
+    #[test]
+    fn status_targets() {
+        let network = network(&["STATUSMSG=@+"]);
+        assert_eq!(network.status_target("@#rust"), "#rust");
+        assert_eq!(network.status_target("@+#rust"), "#rust");
+        assert_eq!(network.status_target("#rust"), "#rust");
+        assert_eq!(network.status_target("@nick"), "@nick");
+        assert_eq!(NetworkInfo::new().status_target("@#rust"), "@#rust");
+    }
+}
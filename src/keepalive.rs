@@ -0,0 +1,135 @@
+use std::time::{Duration, Instant};
+
+use irc::proto::Command;
+
+const DEFAULT_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_STALE_AFTER: Duration = Duration::from_secs(30);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(120);
+// so our PONGs can be told apart from ones the irc crate asked for
+const TOKEN_PREFIX: &str = "keepalive-";
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Health {
+    Ok,
+    // nothing but PINGs and PONGs for a whole interval
+    Idle,
+    // our PING has gone unanswered for a while
+    Stale,
+    // long enough that the connection should be given up on
+    TimedOut,
+}
+
+// sends a PING every interval and times how long the PONG takes
+#[derive(Debug, Clone, Serialize)]
+pub struct Keepalive {
+    #[serde(skip)]
+    interval: Duration,
+    #[serde(skip)]
+    stale_after: Duration,
+    #[serde(skip)]
+    timeout: Duration,
+    #[serde(skip)]
+    last_seen: Option<Instant>,
+    // the last line that wasn't a PING or PONG, our own PINGs keep
+    // last_seen fresh so it can't tell us the connection is quiet
+    #[serde(skip)]
+    last_activity: Option<Instant>,
+    #[serde(skip)]
+    last_ping: Option<Instant>,
+    #[serde(skip)]
+    outstanding: Option<(String, Instant)>,
+    sent: u64,
+    lag_ms: Option<u64>,
+}
+
+impl Default for Keepalive {
+    fn default() -> Self {
+        Keepalive::new(DEFAULT_INTERVAL, DEFAULT_STALE_AFTER, DEFAULT_TIMEOUT)
+    }
+}
+
+impl Keepalive {
+    pub fn new(interval: Duration, stale_after: Duration, timeout: Duration) -> Keepalive {
+        Keepalive {
+            interval,
+            stale_after,
+            timeout,
+            last_seen: None,
+            last_activity: None,
+            last_ping: None,
+            outstanding: None,
+            sent: 0,
+            lag_ms: None,
+        }
+    }
+
+    // the round trip of the last PING we got an answer to
+    pub fn lag(&self) -> Option<Duration> {
+        self.lag_ms.map(Duration::from_millis)
+    }
+
+    // anything at all from the server counts, only real traffic keeps
+    // the connection from looking idle
+    pub fn seen(&mut self, now: Instant, activity: bool) {
+        self.last_seen = Some(now);
+        if activity {
+            self.last_activity = Some(now);
+        }
+    }
+
+    pub fn poll(&mut self, now: Instant) -> Option<Command> {
+        if let Some((_, sent)) = self.outstanding {
+            // the server is still talking but the PONG went missing, ask
+            // again instead of waiting on it forever
+            let heard_since = self.last_seen.map(|seen| seen > sent).unwrap_or(false);
+            if !heard_since || now.duration_since(sent) < self.interval {
+                return None;
+            }
+        }
+        if let Some(last) = self.last_ping {
+            if now.duration_since(last) < self.interval {
+                return None;
+            }
+        }
+        self.sent += 1;
+        let token = format!("{}{}", TOKEN_PREFIX, self.sent);
+        self.last_ping = Some(now);
+        self.outstanding = Some((token.clone(), now));
+        Some(Command::PING(token, None))
+    }
+
+    // false when the PONG wasn't for one of our PINGs
+    pub fn pong(&mut self, token: &str, now: Instant) -> bool {
+        let sent = match self.outstanding {
+            Some((ref outstanding, sent)) if outstanding == token => sent,
+            _ => return false,
+        };
+        self.outstanding = None;
+        self.lag_ms = Some(now.duration_since(sent).as_millis() as u64);
+        true
+    }
+
+    pub fn health(&self, now: Instant) -> Health {
+        // hearing anything at all after the PING went out means the server is still there
+        let waiting = match self.outstanding {
+            Some((_, sent)) if self.last_seen.map(|seen| seen <= sent).unwrap_or(true) => Some(now.duration_since(sent)),
+            _ => None,
+        };
+        let quiet = self.last_activity.map(|s| now.duration_since(s));
+        match (waiting, quiet) {
+            (Some(waiting), _) if waiting >= self.timeout => Health::TimedOut,
+            (Some(waiting), _) if waiting >= self.stale_after => Health::Stale,
+            (_, Some(quiet)) if quiet >= self.interval => Health::Idle,
+            _ => Health::Ok,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.last_seen = None;
+        self.last_activity = None;
+        self.last_ping = None;
+        self.outstanding = None;
+        self.lag_ms = None;
+    }
+}
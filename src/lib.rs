@@ -3,14 +3,38 @@ extern crate serde;
 extern crate serde_derive;
 extern crate serde_json;
 extern crate irc;
+extern crate chrono;
+extern crate base64;
+extern crate regex;
 
 
 
 pub mod server;
 pub mod channel;
 pub mod event;
+pub mod error;
+pub mod ctcp;
+pub mod format;
+pub mod nick;
+pub mod query;
+pub mod tags;
+pub mod cap;
+pub mod sasl;
+pub mod isupport;
+pub mod casemap;
+pub mod modes;
+pub mod mask;
+pub mod hostmask;
+pub mod whois;
+pub mod who;
+pub mod directory;
+pub mod reconnect;
+pub mod keepalive;
+pub mod netsplit;
+pub mod batch;
 
 pub mod prelude {
     pub use server::Server;
     pub use event::Event;
+    pub use nick::NickState;
 }
\ No newline at end of file
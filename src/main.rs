@@ -1,43 +1,181 @@
-#[macro_use]
 extern crate diesel;
 extern crate dotenv;
 extern crate futures;
 extern crate irc;
 extern crate irc_client;
+extern crate native_tls;
 extern crate serde;
 extern crate serde_json;
+extern crate tokio_codec;
 extern crate tokio_core;
+extern crate tokio_io;
+extern crate tokio_tls;
 
 
+use std::env;
 use std::fs::{OpenOptions, File};
-use std::io::Write;
+use std::io::{Read, Write};
 use std::path::PathBuf;
-use std::time::{SystemTime, UNIX_EPOCH, Duration};
+use std::sync::mpsc::{self, TryRecvError};
+use std::thread;
+use std::time::{SystemTime, UNIX_EPOCH, Duration, Instant};
 
 use dotenv::dotenv;
 
 use irc_client::prelude::*;
+use irc_client::ctcp::CtcpResponder;
+use irc_client::reconnect::Backoff;
+use irc_client::sasl::SaslCredentials;
 use irc::client::prelude::*;
+use irc::proto::line::LineCodec;
+use native_tls::Identity;
 use serde_json::to_string;
+use tokio_codec::FramedRead;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::Core;
+use tokio_io::{AsyncRead, AsyncWrite};
+use tokio_io::io::write_all;
+use tokio_tls::TlsConnector;
+
+// how often the server gets a chance to check on the connection when
+// nothing is arriving
+const TICK: Duration = Duration::from_secs(1);
 
 fn main() {
     dotenv().ok();
     let start_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
     write_entry(format!("{{\"type\": \"started\", \"args\": [{}]}}", start_time.as_secs()));
+    let nick = NickState::with_alternates("fruitbot", vec!["fruit_bot".to_owned()]);
+    // SASL EXTERNAL logs in with a client certificate, which needs TLS,
+    // and PLAIN sends the password so it can't go out in the clear either
+    let client_cert = env::var("CLIENT_CERT_PATH").ok();
+    let credentials = SaslCredentials::from_env();
+    let tls = client_cert.is_some() || credentials.is_some();
     let config = Config {
-        nickname: Some("fruitbot".to_owned()),
+        nickname: Some(nick.preferred().to_owned()),
         server: Some("irc.mozilla.org".to_owned()),
-        channels: Some(vec!["#rust".to_owned(), "#rust_embedded".to_owned()]),
+        use_ssl: Some(tls),
+        port: if tls { Some(6697) } else { None },
+        client_cert_pass: client_cert.as_ref().and_then(|_| env::var("CLIENT_CERT_PASS").ok()),
+        client_cert_path: client_cert,
         ..Config::default()
     };
-    let client = IrcClient::from_config(config).expect("Unable to create client");
-    client.identify().expect("Unable to identify client");
     let mut server = Server::with(Box::new(listener));
-    client.for_each_incoming(|msg| {
-        server.handle_message(msg);
-    }).expect("Unable to register incoming handler");
+    server.set_nick_state(nick);
+    server.join_on_connect("#rust", None);
+    server.join_on_connect("#rust_embedded", None);
+    if let Some(credentials) = credentials {
+        server.set_sasl(credentials);
+    }
+    server.set_ctcp_responder(CtcpResponder::new("fruitbot 0.1.0", "https://github.com/FreeMasen/toy_irc"));
+    let mut backoff = Backoff::default();
+    loop {
+        let reason = run(&config, &mut server);
+        // only back off further while we can't get registered at all
+        if server.is_connected() {
+            backoff.reset();
+        }
+        server.disconnected(reason);
+        let delay = backoff.next_delay();
+        server.reconnecting(backoff.attempt(), delay);
+        thread::sleep(delay);
+    }
 }
 
+// one connection from start to finish, returns why it ended. The
+// connection lives on a reactor owned by this call so the socket is closed
+// as soon as we return
+fn run(config: &Config, server: &mut Server) -> Option<String> {
+    let mut reactor = match Core::new() {
+        Ok(reactor) => reactor,
+        Err(e) => return Some(format!("Unable to start reactor: {}", e)),
+    };
+    let handle = reactor.handle();
+    let addr = match config.socket_addr() {
+        Ok(addr) => addr,
+        Err(e) => return Some(format!("Unable to connect: {}", e)),
+    };
+    let socket = match reactor.run(TcpStream::connect(&addr, &handle)) {
+        Ok(socket) => socket,
+        Err(e) => return Some(format!("Unable to connect: {}", e)),
+    };
+    if !config.use_ssl() {
+        return run_on(reactor, socket, server);
+    }
+    let connector = match tls_connector(config) {
+        Ok(connector) => connector,
+        Err(e) => return Some(format!("Unable to set up TLS: {}", e)),
+    };
+    match reactor.run(connector.connect(config.server().unwrap_or_default(), socket)) {
+        Ok(stream) => run_on(reactor, stream, server),
+        Err(e) => Some(format!("Unable to connect: {}", e)),
+    }
+}
+
+fn tls_connector(config: &Config) -> Result<TlsConnector, String> {
+    let mut builder = native_tls::TlsConnector::builder();
+    if let Some(path) = config.client_cert_path() {
+        let mut data = Vec::new();
+        File::open(path).and_then(|mut f| f.read_to_end(&mut data)).map_err(|e| e.to_string())?;
+        let identity = Identity::from_pkcs12(&data, config.client_cert_pass()).map_err(|e| e.to_string())?;
+        builder.identity(identity);
+    }
+    builder.build().map(TlsConnector::from).map_err(|e| e.to_string())
+}
+
+// we read the lines ourselves, the irc crate's own client throws away the
+// arguments of modes it doesn't know take one before we ever see them
+fn run_on<S: AsyncRead + AsyncWrite + 'static>(mut reactor: Core, stream: S, server: &mut Server) -> Option<String> {
+    let (reader, mut writer) = stream.split();
+    let (outgoing, commands) = mpsc::channel::<Command>();
+    server.set_sender(Box::new(move |cmd| {
+        let _ = outgoing.send(cmd);
+    }));
+    server.register("fruitbot", "fruitbot");
+    // incoming lines are handed over a channel so the reactor only has to
+    // run for a tick at a time and the connection still gets checked on
+    // while nothing is arriving. Err means the connection is over
+    let (tx, lines) = mpsc::channel();
+    let closed = tx.clone();
+    let codec = match LineCodec::new("UTF-8") {
+        Ok(codec) => codec,
+        Err(e) => return Some(e.to_string()),
+    };
+    reactor.handle().spawn(FramedRead::new(reader, codec)
+        .map_err(|e| Some(e.to_string()))
+        .for_each(move |line| tx.send(Ok(line)).map_err(|_| None))
+        .then(move |end| {
+            let _ = closed.send(Err(end.err().and_then(|e| e)));
+            Ok(())
+        }));
+    loop {
+        let mut pending = String::new();
+        for cmd in commands.try_iter() {
+            pending.push_str(&Message::from(cmd).to_string());
+        }
+        if !pending.is_empty() {
+            writer = match reactor.run(write_all(writer, pending.into_bytes())) {
+                Ok((writer, _)) => writer,
+                Err(e) => return Some(format!("Unable to send: {}", e)),
+            };
+        }
+        let now = Instant::now();
+        if server.should_reconnect(now) {
+            let _ = reactor.run(write_all(writer, Message::from(Command::QUIT(Some(String::from("Ping timeout")))).to_string()));
+            return Some(String::from("Ping timeout"));
+        }
+        reactor.turn(Some(TICK));
+        loop {
+            match lines.try_recv() {
+                Ok(Ok(line)) => server.handle_line(&line),
+                Ok(Err(reason)) => return reason,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return None,
+            }
+        }
+        server.tick(Instant::now());
+    }
+}
 
 fn listener(ev: Event) {
     match ev {
@@ -47,7 +185,7 @@ fn listener(ev: Event) {
         // Event::NewMessage(ch, message) => print!(",\n\"{} msg: {:?}\"", ch, to_string(&message)),
         _ => {
             match to_string(&ev) {
-                Ok(ev_str) => write_entry(format!("{}", ev_str)),
+                Ok(ev_str) => write_entry(ev_str),
                 _ => write_entry(String::from("{\"type\": \"error\", \"args\": [\"Unable to convert ev to json\"]}"))
             }
         },
@@ -68,5 +206,5 @@ fn write_entry(line: String) {
             };
 
     let mut f = OpenOptions::new().append(true).open(path).expect("Unable to open file");
-    let _size = f.write_all(format!("{}{}", prefix, line).as_bytes()).expect("Unable to write to file");
+    f.write_all(format!("{}{}", prefix, line).as_bytes()).expect("Unable to write to file");
 }
\ No newline at end of file
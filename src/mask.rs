@@ -0,0 +1,203 @@
+use casemap::CaseMapping;
+use channel::ChannelUser;
+
+// a ban, exception or invite mask, either nick!user@host or one of the
+// common extbans
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+#[serde(tag = "type", content = "value", rename_all = "kebab-case")]
+pub enum Mask {
+    Host(String),
+    // $a on its own is anyone logged in
+    Account(Option<String>),
+    RealName(String),
+    // $x, nick!user@host#realname
+    Full(String),
+    // $j, anyone banned from the other channel
+    Channel(String),
+    Not(Box<Mask>),
+    Unknown(String),
+}
+
+impl Mask {
+    // prefix is the EXTBAN prefix, None for networks that write extbans
+    // as just "a:account"
+    pub fn parse(text: &str, prefix: Option<char>) -> Mask {
+        let ext = match prefix {
+            Some(prefix) if text.starts_with(prefix) => &text[prefix.len_utf8()..],
+            Some(_) => return Mask::Host(String::from(text)),
+            None if is_bare_extban(text) => text,
+            None => return Mask::Host(String::from(text)),
+        };
+        if let Some(rest) = ext.strip_prefix('~') {
+            return Mask::Not(Box::new(Mask::ext(rest, text)));
+        }
+        Mask::ext(ext, text)
+    }
+
+    fn ext(ext: &str, text: &str) -> Mask {
+        let mut parts = ext.splitn(2, ':');
+        let kind = parts.next().unwrap_or("");
+        let arg = parts.next().filter(|a| !a.is_empty()).map(String::from);
+        match (kind, arg) {
+            ("a", arg) => Mask::Account(arg),
+            ("r", Some(arg)) => Mask::RealName(arg),
+            ("x", Some(arg)) => Mask::Full(arg),
+            ("j", Some(arg)) => Mask::Channel(arg),
+            _ => Mask::Unknown(String::from(text)),
+        }
+    }
+
+    pub fn is_extban(&self) -> bool {
+        !matches!(*self, Mask::Host(_))
+    }
+
+    // banned_in answers $j for the user being checked, extbans we don't
+    // understand never match
+    pub fn matches(&self, user: &ChannelUser, case_mapping: CaseMapping, banned_in: &dyn Fn(&str) -> bool) -> bool {
+        match *self {
+            Mask::Host(ref mask) => user.hostmask().matches(mask, case_mapping),
+            Mask::Account(None) => user.account().is_some(),
+            Mask::Account(Some(ref mask)) => user.account().map(|a| matches(mask, a, case_mapping)).unwrap_or(false),
+            // real names aren't nicks so only ascii case is ignored
+            Mask::RealName(ref mask) => user.real_name().map(|r| matches(mask, r, CaseMapping::Ascii)).unwrap_or(false),
+            Mask::Full(ref mask) => {
+                let full = format!("{}#{}", user.hostmask(), user.real_name().unwrap_or(""));
+                matches(mask, &full, case_mapping)
+            },
+            Mask::Channel(ref channel) => banned_in(channel),
+            Mask::Not(ref mask) => !mask.matches(user, case_mapping, banned_in),
+            Mask::Unknown(_) => false,
+        }
+    }
+}
+
+// "a:account" rather than a nick!user@host
+fn is_bare_extban(text: &str) -> bool {
+    let mut chars = text.chars();
+    let first = match chars.next() {
+        Some('~') => chars.next(),
+        c => c,
+    };
+    let second = chars.next();
+    first.map(|c| c.is_ascii_alphabetic()).unwrap_or(false)
+        && (second == Some(':') || second.is_none())
+        && !text.contains('!')
+        && !text.contains('@')
+}
+
+// does nick!user@host fit a ban style mask, * matches any run of
+// characters and ? exactly one
+pub fn matches(mask: &str, hostmask: &str, case_mapping: CaseMapping) -> bool {
+    let mask: Vec<char> = case_mapping.fold(mask).chars().collect();
+    let text: Vec<char> = case_mapping.fold(hostmask).chars().collect();
+    glob(&mask, &text)
+}
+
+fn glob(mask: &[char], text: &[char]) -> bool {
+    let (mut m, mut t) = (0, 0);
+    // where to pick up again if what followed the last * stops matching
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        match mask.get(m) {
+            Some('*') => {
+                star = Some((m, t));
+                m += 1;
+            },
+            Some(&c) if c == '?' || c == text[t] => {
+                m += 1;
+                t += 1;
+            },
+            _ => match star {
+                Some((star_m, star_t)) => {
+                    m = star_m + 1;
+                    t = star_t + 1;
+                    star = Some((star_m, star_t + 1));
+                },
+                None => return false,
+            },
+        }
+    }
+    mask[m..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use channel::Channel;
+    use hostmask::Hostmask;
+    use modes::{self, ListEntry};
+
+    fn channel() -> Channel {
+        let mut channel = Channel::with_name("#rust");
+        channel.join_user(&Hostmask::parse("Bob!bob@example.com"));
+        channel.set_user_account("Bob", Some("bobby"));
+        channel.set_user_real_name("Bob", "Bob Smith");
+        channel.join_user(&Hostmask::parse("guest!~guest@10.0.0.1"));
+        channel
+    }
+
+    fn hits(mask: &str, nick: &str) -> bool {
+        let channel = channel();
+        let user = channel.user(nick).expect("user");
+        Mask::parse(mask, Some('$')).matches(user, CaseMapping::Rfc1459, &|channel| channel == "#banned")
+    }
+
+    #[test]
+    fn glob_matches() {
+        let rfc = CaseMapping::Rfc1459;
+        assert!(matches("*", "", rfc));
+        assert!(matches("*!*@*", "nick!user@host", rfc));
+        assert!(matches("n?ck!*@*.com", "nick!user@example.com", rfc));
+        assert!(!matches("n?ck!*@*.com", "nck!user@example.com", rfc));
+        assert!(matches("*a*b*c", "xaxxbyc", rfc));
+        assert!(!matches("*a*b*c", "xaxxbycd", rfc));
+        assert!(matches("NICK[]!*@*", "nick{}!u@h", rfc));
+        assert!(!matches("NICK[]!*@*", "nick{}!u@h", CaseMapping::Ascii));
+        assert!(!matches("abc", "ab", rfc));
+    }
+
+    #[test]
+    fn parse_extbans() {
+        assert_eq!(Mask::parse("*!*@host", Some('$')), Mask::Host(String::from("*!*@host")));
+        assert_eq!(Mask::parse("$a", Some('$')), Mask::Account(None));
+        assert_eq!(Mask::parse("$a:bob*", Some('$')), Mask::Account(Some(String::from("bob*"))));
+        assert_eq!(Mask::parse("$r:*bot*", Some('$')), Mask::RealName(String::from("*bot*")));
+        assert_eq!(Mask::parse("$x:*!*@*#*bot*", Some('$')), Mask::Full(String::from("*!*@*#*bot*")));
+        assert_eq!(Mask::parse("$j:#other", Some('$')), Mask::Channel(String::from("#other")));
+        assert_eq!(Mask::parse("$~a", Some('$')), Mask::Not(Box::new(Mask::Account(None))));
+        assert_eq!(Mask::parse("$z:what", Some('$')), Mask::Unknown(String::from("$z:what")));
+        // no prefix, extbans are written bare
+        assert_eq!(Mask::parse("a:bob", None), Mask::Account(Some(String::from("bob"))));
+        assert_eq!(Mask::parse("~a", None), Mask::Not(Box::new(Mask::Account(None))));
+        assert_eq!(Mask::parse("a:b!c@d", None), Mask::Host(String::from("a:b!c@d")));
+        // with a prefix anything without it is a hostmask
+        assert_eq!(Mask::parse("a:bob", Some('$')), Mask::Host(String::from("a:bob")));
+    }
+
+    #[test]
+    fn match_users() {
+        assert!(hits("*!*@example.com", "bob"));
+        assert!(!hits("*!*@example.com", "guest"));
+        assert!(hits("$a", "Bob"));
+        assert!(!hits("$a", "guest"));
+        assert!(hits("$~a", "guest"));
+        assert!(hits("$a:BOB*", "Bob"));
+        assert!(hits("$r:bob *", "Bob"));
+        assert!(hits("$x:bob!*@*#*smith", "Bob"));
+        assert!(hits("$j:#banned", "guest"));
+        assert!(!hits("$j:#other", "guest"));
+        assert!(!hits("$z:anything", "Bob"));
+    }
+
+    #[test]
+    fn exceptions_use_extbans() {
+        let mut channel = channel();
+        channel.list_entry(modes::BAN, ListEntry::new("*!*@*", None, None));
+        channel.end_list(modes::BAN);
+        channel.list_entry(modes::EXCEPTION, ListEntry::new("$a:bobby", None, None));
+        channel.end_list(modes::EXCEPTION);
+        let bans = |nick: &str| channel.bans_for(channel.user(nick).expect("user"), Some('$'), &|_| false).len();
+        assert_eq!(bans("Bob"), 0);
+        assert_eq!(bans("guest"), 1);
+    }
+}
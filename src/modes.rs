@@ -0,0 +1,268 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use isupport::{ModeKind, NetworkInfo};
+
+// the usual letters, networks can use others and those still work through
+// list, param and has
+pub const BAN: char = 'b';
+pub const EXCEPTION: char = 'e';
+pub const INVITE_EXCEPTION: char = 'I';
+pub const QUIET: char = 'q';
+const KEY: char = 'k';
+const LIMIT: char = 'l';
+const FORWARD: char = 'f';
+
+#[derive(Debug, Clone, Serialize, PartialEq)]
+pub struct ListEntry {
+    pub mask: String,
+    pub set_by: Option<String>,
+    pub set_at: Option<u64>,
+}
+
+impl ListEntry {
+    pub fn new(mask: &str, set_by: Option<&str>, set_at: Option<u64>) -> ListEntry {
+        ListEntry {
+            mask: String::from(mask),
+            set_by: set_by.map(String::from),
+            set_at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct ChannelModes {
+    // A: masks kept by the channel
+    lists: BTreeMap<char, Vec<ListEntry>>,
+    // list replies that have arrived but not their end marker yet
+    #[serde(skip)]
+    syncing: BTreeMap<char, Vec<ListEntry>>,
+    // B and C: modes with a value
+    params: BTreeMap<char, String>,
+    // D: on or off
+    flags: BTreeSet<char>,
+}
+
+impl ChannelModes {
+    pub fn new() -> ChannelModes {
+        ChannelModes::default()
+    }
+
+    // prefix modes belong to users and are ignored here, a mode the network
+    // never told us about is treated as a flag unless it came with a value.
+    // set_by and set_at are only kept for list entries.
+    pub fn apply(&mut self, adding: bool, mode: char, arg: Option<&str>, kind: Option<ModeKind>, set_by: &str, set_at: u64) -> bool {
+        let kind = match kind {
+            Some(kind) => kind,
+            None if arg.is_some() => ModeKind::AlwaysArg,
+            None => ModeKind::Flag,
+        };
+        match kind {
+            ModeKind::Prefix => false,
+            ModeKind::List => {
+                let mask = match arg {
+                    Some(mask) => mask,
+                    None => return false,
+                };
+                let list = self.lists.entry(mode).or_default();
+                let exists = list.iter().any(|e| e.mask == mask);
+                if adding && !exists {
+                    list.push(ListEntry::new(mask, Some(set_by), Some(set_at)));
+                    true
+                } else if !adding && exists {
+                    list.retain(|e| e.mask != mask);
+                    true
+                } else {
+                    false
+                }
+            },
+            ModeKind::AlwaysArg | ModeKind::SetArg => {
+                if adding {
+                    let value = String::from(arg.unwrap_or_default());
+                    self.params.insert(mode, value.clone()) != Some(value)
+                } else {
+                    self.params.remove(&mode).is_some()
+                }
+            },
+            ModeKind::Flag => {
+                if adding {
+                    self.flags.insert(mode)
+                } else {
+                    self.flags.remove(&mode)
+                }
+            },
+        }
+    }
+
+    // RPL_CHANNELMODEIS lists every flag and value at once, lists are
+    // only ever sent on their own
+    pub fn clear_settings(&mut self) {
+        self.params.clear();
+        self.flags.clear();
+    }
+
+    pub fn clear(&mut self) {
+        self.lists.clear();
+        self.syncing.clear();
+        self.clear_settings();
+    }
+
+    // one line of RPL_BANLIST and friends, the whole list is swapped in
+    // once end_list arrives so entries removed since last time go away
+    pub fn list_entry(&mut self, mode: char, entry: ListEntry) {
+        self.syncing.entry(mode).or_default().push(entry);
+    }
+
+    pub fn end_list(&mut self, mode: char) {
+        let entries = self.syncing.remove(&mode).unwrap_or_default();
+        self.lists.insert(mode, entries);
+    }
+
+    pub fn has(&self, mode: char) -> bool {
+        self.flags.contains(&mode) || self.params.contains_key(&mode)
+    }
+
+    pub fn param(&self, mode: char) -> Option<&str> {
+        self.params.get(&mode).map(|p| p.as_str())
+    }
+
+    pub fn list(&self, mode: char) -> &[ListEntry] {
+        self.lists.get(&mode).map(|l| l.as_slice()).unwrap_or(&[])
+    }
+
+    pub fn bans(&self) -> &[ListEntry] {
+        self.list(BAN)
+    }
+
+    pub fn exceptions(&self) -> &[ListEntry] {
+        self.list(EXCEPTION)
+    }
+
+    pub fn invite_exceptions(&self) -> &[ListEntry] {
+        self.list(INVITE_EXCEPTION)
+    }
+
+    pub fn quiets(&self) -> &[ListEntry] {
+        self.list(QUIET)
+    }
+
+    pub fn key(&self) -> Option<&str> {
+        self.param(KEY)
+    }
+
+    pub fn limit(&self) -> Option<u32> {
+        self.param(LIMIT).and_then(|l| l.parse().ok())
+    }
+
+    pub fn forward(&self) -> Option<&str> {
+        self.param(FORWARD)
+    }
+
+    // flags first, then modes that only have a value while set, then ones
+    // that always have a value, each in the order the network lists them.
+    // e.g. "+ntlk 50 secret"
+    pub fn render(&self, network: &NetworkInfo) -> String {
+        let rank = |mode: &char| {
+            let group = match network.mode_kind(*mode) {
+                Some(ModeKind::Flag) => 0,
+                Some(ModeKind::SetArg) => 1,
+                Some(ModeKind::AlwaysArg) => 2,
+                _ => 3,
+            };
+            (group, network.mode_position(*mode), *mode)
+        };
+        let mut modes: Vec<char> = self.flags.iter().chain(self.params.keys()).cloned().collect();
+        modes.sort_by_key(|m| rank(m));
+        if modes.is_empty() {
+            return String::new();
+        }
+        let mut ret = String::from("+");
+        ret.extend(modes.iter());
+        for mode in modes {
+            if let Some(value) = self.params.get(&mode) {
+                ret.push(' ');
+                ret.push_str(value);
+            }
+        }
+        ret
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hostmask::Hostmask;
+    use server::Server;
+
+    fn masks(entries: &[ListEntry]) -> Vec<&str> {
+        entries.iter().map(|e| e.mask.as_str()).collect()
+    }
+
+    #[test]
+    fn list_modes() {
+        let mut modes = ChannelModes::new();
+        assert!(modes.apply(true, BAN, Some("*!*@a"), Some(ModeKind::List), "op", 1));
+        assert!(!modes.apply(true, BAN, Some("*!*@a"), Some(ModeKind::List), "op", 2));
+        assert!(modes.apply(true, BAN, Some("*!*@b"), Some(ModeKind::List), "op", 3));
+        assert!(!modes.apply(true, BAN, None, Some(ModeKind::List), "op", 4));
+        assert_eq!(masks(modes.bans()), vec!["*!*@a", "*!*@b"]);
+        assert_eq!(modes.bans()[0], ListEntry::new("*!*@a", Some("op"), Some(1)));
+        assert!(modes.apply(false, BAN, Some("*!*@a"), Some(ModeKind::List), "op", 5));
+        assert!(!modes.apply(false, BAN, Some("*!*@a"), Some(ModeKind::List), "op", 6));
+        assert_eq!(masks(modes.bans()), vec!["*!*@b"]);
+        assert!(modes.exceptions().is_empty());
+    }
+
+    #[test]
+    fn list_replies_replace_the_list() {
+        let mut modes = ChannelModes::new();
+        modes.apply(true, BAN, Some("*!*@gone"), Some(ModeKind::List), "op", 1);
+        modes.list_entry(BAN, ListEntry::new("*!*@a", Some("op"), Some(2)));
+        modes.list_entry(BAN, ListEntry::new("*!*@b", None, None));
+        // nothing changes until the end of the list
+        assert_eq!(masks(modes.bans()), vec!["*!*@gone"]);
+        modes.end_list(BAN);
+        assert_eq!(masks(modes.bans()), vec!["*!*@a", "*!*@b"]);
+        // an empty list clears it
+        modes.end_list(BAN);
+        assert!(modes.bans().is_empty());
+    }
+
+    #[test]
+    fn settings_keep_lists() {
+        let mut modes = ChannelModes::new();
+        modes.apply(true, EXCEPTION, Some("$a:bob"), Some(ModeKind::List), "op", 1);
+        modes.apply(true, KEY, Some("secret"), Some(ModeKind::AlwaysArg), "op", 1);
+        modes.apply(true, 'n', None, None, "op", 1);
+        assert_eq!(modes.key(), Some("secret"));
+        assert!(modes.has('n'));
+        modes.clear_settings();
+        assert!(modes.key().is_none() && !modes.has('n'));
+        assert_eq!(masks(modes.exceptions()), vec!["$a:bob"]);
+        modes.clear();
+        assert!(modes.exceptions().is_empty());
+    }
+
+    #[test]
+    fn bans_for_someone_outside() {
+        let mut server = Server::new();
+        for line in &[
+            ":srv 001 bot :Welcome",
+            ":bot!u@h JOIN #a",
+            ":bot!u@h JOIN #b",
+            ":op!u@h MODE #a +bbe *!*@bad.host $j:#b $a:friend",
+            ":op!u@h MODE #b +b *!*@other.host",
+        ] {
+            server.handle_line(line);
+        }
+        let bans = |mask: &str, account: Option<&str>| {
+            server.bans_matching("#a", &Hostmask::parse(mask), account).into_iter().map(|e| e.mask).collect::<Vec<_>>()
+        };
+        assert_eq!(bans("nobody!u@bad.host", None), vec!["*!*@bad.host"]);
+        assert_eq!(bans("nobody!u@other.host", None), vec!["$j:#b"]);
+        assert!(bans("nobody!u@good.host", None).is_empty());
+        // the exception lets them in whatever the bans say
+        assert!(bans("nobody!u@bad.host", Some("friend")).is_empty());
+        assert!(server.bans_for("#a", "nobody").is_empty());
+        assert!(server.bans_matching("#elsewhere", &Hostmask::parse("nobody!u@bad.host"), None).is_empty());
+    }
+}
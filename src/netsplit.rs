@@ -0,0 +1,332 @@
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+use casemap::CaseMapping;
+
+// a burst of split quits or rejoins is over once nothing new has come in for this long
+const SETTLE: Duration = Duration::from_secs(3);
+// how long we expect nicks lost in a split to come back
+const REMEMBER: Duration = Duration::from_secs(30 * 60);
+
+// the servers, nicks and channels caught up in one split or rejoin
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct NetGroup {
+    pub servers: Vec<String>,
+    pub nicks: Vec<String>,
+    pub channels: BTreeSet<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetChange {
+    Split(NetGroup),
+    Join(NetGroup),
+}
+
+#[derive(Debug, Clone)]
+struct Burst {
+    group: NetGroup,
+    // bursts sent as a BATCH end when the batch does
+    batch: Option<String>,
+    last: Instant,
+}
+
+impl Burst {
+    fn new(servers: Vec<String>, batch: Option<&str>, now: Instant) -> Burst {
+        Burst {
+            group: NetGroup {
+                servers,
+                ..NetGroup::default()
+            },
+            batch: batch.map(String::from),
+            last: now,
+        }
+    }
+
+    fn add(&mut self, nick: &str, channels: &[String], case_mapping: CaseMapping, now: Instant) {
+        if !self.group.nicks.iter().any(|n| case_mapping.eq(n, nick)) {
+            self.group.nicks.push(String::from(nick));
+        }
+        self.group.channels.extend(channels.iter().cloned());
+        self.last = now;
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct NetsplitTracker {
+    splits: Vec<Burst>,
+    joins: Vec<Burst>,
+    // nicks that left in a split, with the servers involved
+    gone: Vec<(String, Vec<String>, Instant)>,
+    case_mapping: CaseMapping,
+}
+
+impl NetsplitTracker {
+    pub fn new() -> NetsplitTracker {
+        NetsplitTracker::default()
+    }
+
+    pub fn set_case_mapping(&mut self, case_mapping: CaseMapping) {
+        self.case_mapping = case_mapping;
+    }
+
+    // a BATCH of type netsplit or netjoin, params are the two servers
+    pub fn start_batch(&mut self, reference: &str, kind: &str, servers: &[String], now: Instant) {
+        let burst = Burst::new(servers.to_vec(), Some(reference), now);
+        if kind.eq_ignore_ascii_case("netsplit") {
+            self.splits.push(burst);
+        } else if kind.eq_ignore_ascii_case("netjoin") {
+            self.joins.push(burst);
+        }
+    }
+
+    pub fn end_batch(&mut self, reference: &str) -> Vec<NetChange> {
+        let mut ret = Vec::new();
+        if let Some(i) = self.splits.iter().position(|b| b.batch.as_deref() == Some(reference)) {
+            let burst = self.splits.remove(i);
+            ret.push(NetChange::Split(self.remember(burst)));
+        }
+        if let Some(i) = self.joins.iter().position(|b| b.batch.as_deref() == Some(reference)) {
+            let burst = self.joins.remove(i);
+            ret.push(NetChange::Join(self.returned(burst)));
+        }
+        ret
+    }
+
+    // true when the quit is part of a split and shouldn't be reported on its own
+    pub fn quit(&mut self, nick: &str, reason: Option<&str>, batch: Option<&str>, channels: &[String], now: Instant) -> bool {
+        let case_mapping = self.case_mapping;
+        if let Some(batch) = batch {
+            if let Some(burst) = self.splits.iter_mut().find(|b| b.batch.as_deref() == Some(batch)) {
+                burst.add(nick, channels, case_mapping, now);
+                return true;
+            }
+        }
+        let servers = match reason.and_then(split_servers) {
+            Some(servers) => servers,
+            None => return false,
+        };
+        let pos = self.splits.iter().position(|b| b.batch.is_none() && b.group.servers == servers);
+        let burst = match pos {
+            Some(i) => &mut self.splits[i],
+            None => {
+                self.splits.push(Burst::new(servers, None, now));
+                self.splits.last_mut().expect("just pushed")
+            },
+        };
+        burst.add(nick, channels, case_mapping, now);
+        true
+    }
+
+    // true when someone lost in a split is coming back
+    pub fn join(&mut self, nick: &str, channel: &str, batch: Option<&str>, now: Instant) -> bool {
+        let channels = [String::from(channel)];
+        let case_mapping = self.case_mapping;
+        if let Some(batch) = batch {
+            if let Some(burst) = self.joins.iter_mut().find(|b| b.batch.as_deref() == Some(batch)) {
+                burst.add(nick, &channels, case_mapping, now);
+                return true;
+            }
+        }
+        let servers = match self.gone.iter().find(|g| case_mapping.eq(&g.0, nick)) {
+            Some(gone) => gone.1.clone(),
+            None => return false,
+        };
+        let pos = self.joins.iter().position(|b| b.batch.is_none() && b.group.servers == servers);
+        let burst = match pos {
+            Some(i) => &mut self.joins[i],
+            None => {
+                self.joins.push(Burst::new(servers, None, now));
+                self.joins.last_mut().expect("just pushed")
+            },
+        };
+        burst.add(nick, &channels, case_mapping, now);
+        true
+    }
+
+    // bursts that have gone quiet, bursts from a BATCH wait for the end of it
+    pub fn flush(&mut self, now: Instant) -> Vec<NetChange> {
+        let settled = |b: &Burst| b.batch.is_none() && now.duration_since(b.last) >= SETTLE;
+        let mut ret = Vec::new();
+        let (done, splits): (Vec<Burst>, Vec<Burst>) = self.splits.drain(..).partition(|b| settled(b));
+        self.splits = splits;
+        for burst in done {
+            ret.push(NetChange::Split(self.remember(burst)));
+        }
+        let (done, joins): (Vec<Burst>, Vec<Burst>) = self.joins.drain(..).partition(|b| settled(b));
+        self.joins = joins;
+        for burst in done {
+            ret.push(NetChange::Join(self.returned(burst)));
+        }
+        self.gone.retain(|g| now.duration_since(g.2) < REMEMBER);
+        ret
+    }
+
+    fn remember(&mut self, burst: Burst) -> NetGroup {
+        for nick in &burst.group.nicks {
+            self.gone.push((nick.clone(), burst.group.servers.clone(), burst.last));
+        }
+        burst.group
+    }
+
+    // once the netjoin is over any more joins are ordinary ones
+    fn returned(&mut self, burst: Burst) -> NetGroup {
+        let case_mapping = self.case_mapping;
+        let nicks = &burst.group.nicks;
+        self.gone.retain(|g| !nicks.iter().any(|n| case_mapping.eq(n, &g.0)));
+        burst.group
+    }
+
+    pub fn clear(&mut self) {
+        self.splits.clear();
+        self.joins.clear();
+        self.gone.clear();
+    }
+}
+
+// split quits look like "irc.a.net irc.b.net", the two servers either side,
+// a hidden server can be masked as "*.a.net" but "*.net *.split" is just a quit
+pub fn split_servers(reason: &str) -> Option<Vec<String>> {
+    let servers: Vec<&str> = reason.split(' ').collect();
+    let is_host = |s: &str| {
+        let labels = s.strip_prefix("*.").unwrap_or(s).split('.').collect::<Vec<&str>>();
+        labels.len() >= 2
+            && labels.iter().all(|l| !l.is_empty() && l.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'))
+    };
+    match servers.as_slice() {
+        [a, b] if a != b && is_host(a) && is_host(b) => Some(vec![String::from(*a), String::from(*b)]),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn channels(names: &[&str]) -> Vec<String> {
+        names.iter().map(|c| String::from(*c)).collect()
+    }
+
+    #[test]
+    fn split_reasons() {
+        assert_eq!(split_servers("irc.a.net irc.b.net"), Some(vec![String::from("irc.a.net"), String::from("irc.b.net")]));
+        assert_eq!(split_servers("*.a.net hub.b-c.org"), Some(vec![String::from("*.a.net"), String::from("hub.b-c.org")]));
+        assert_eq!(split_servers("a.b c.d"), Some(vec![String::from("a.b"), String::from("c.d")]));
+    }
+
+    #[test]
+    fn split_look_alikes() {
+        for reason in &[
+            "Quit: a.b c.d",
+            "*.net *.split",
+            "a.b a.b",
+            "a.b",
+            "a.b  c.d",
+            "a.b c.d ",
+            "see you.later",
+            "a..b c.d",
+            ".a.b c.d",
+            "a.b c.d.",
+            "a.*.b c.d",
+            "http://a.b c.d",
+            "",
+        ] {
+            assert_eq!(split_servers(reason), None, "{:?}", reason);
+        }
+    }
+
+    #[test]
+    fn split_quits_are_grouped() {
+        let start = Instant::now();
+        let mut tracker = NetsplitTracker::new();
+        assert!(tracker.quit("alice", Some("hub.net leaf.net"), None, &channels(&["#a"]), start));
+        assert!(tracker.quit("bob", Some("hub.net leaf.net"), None, &channels(&["#a", "#b"]), start + Duration::from_secs(2)));
+        assert!(tracker.quit("carol", Some("hub.net other.net"), None, &channels(&["#c"]), start + Duration::from_secs(2)));
+        assert!(!tracker.quit("dave", Some("Quit: hub.net leaf.net"), None, &channels(&["#a"]), start));
+        assert!(!tracker.quit("erin", None, None, &channels(&["#a"]), start));
+
+        // each burst waits until it has been quiet for a while
+        assert!(tracker.flush(start + Duration::from_secs(4)).is_empty());
+        let changes = tracker.flush(start + Duration::from_secs(5));
+        assert_eq!(changes.len(), 2);
+        match changes[0] {
+            NetChange::Split(ref group) => {
+                assert_eq!(group.servers, vec!["hub.net", "leaf.net"]);
+                assert_eq!(group.nicks, vec!["alice", "bob"]);
+                assert_eq!(group.channels.iter().collect::<Vec<_>>(), vec!["#a", "#b"]);
+            },
+            ref other => panic!("{:?}", other),
+        }
+        assert!(tracker.flush(start + Duration::from_secs(10)).is_empty());
+    }
+
+    #[test]
+    fn netjoin_folds_returning_nicks() {
+        let start = Instant::now();
+        let mut tracker = NetsplitTracker::new();
+        tracker.quit("Alice", Some("hub.net leaf.net"), None, &channels(&["#a"]), start);
+        tracker.quit("bob", Some("hub.net leaf.net"), None, &channels(&["#a"]), start);
+        tracker.flush(start + Duration::from_secs(5));
+
+        let back = start + Duration::from_secs(60);
+        assert!(tracker.join("alice", "#a", None, back));
+        assert!(tracker.join("ALICE", "#b", None, back));
+        assert!(tracker.join("bob", "#a", None, back + Duration::from_secs(1)));
+        assert!(!tracker.join("carol", "#a", None, back));
+        assert!(tracker.flush(back + Duration::from_secs(2)).is_empty());
+        let changes = tracker.flush(back + Duration::from_secs(4));
+        assert_eq!(changes, vec![NetChange::Join(NetGroup {
+            servers: vec![String::from("hub.net"), String::from("leaf.net")],
+            nicks: vec![String::from("alice"), String::from("bob")],
+            channels: channels(&["#a", "#b"]).into_iter().collect(),
+        })]);
+
+        // after the netjoin, joining again is nothing special
+        assert!(!tracker.join("alice", "#c", None, back + Duration::from_secs(10)));
+    }
+
+    #[test]
+    fn split_nicks_are_forgotten() {
+        let start = Instant::now();
+        let mut tracker = NetsplitTracker::new();
+        tracker.quit("alice", Some("hub.net leaf.net"), None, &channels(&["#a"]), start);
+        tracker.flush(start + SETTLE);
+        tracker.flush(start + REMEMBER);
+        assert!(!tracker.join("alice", "#a", None, start + REMEMBER));
+    }
+
+    #[test]
+    fn returning_nicks_use_the_case_mapping() {
+        let start = Instant::now();
+        let mut tracker = NetsplitTracker::new();
+        tracker.set_case_mapping(CaseMapping::StrictRfc1459);
+        tracker.quit("dan~", Some("hub.net leaf.net"), None, &channels(&["#a"]), start);
+        tracker.flush(start + SETTLE);
+        assert!(!tracker.join("dan^", "#a", None, start + SETTLE));
+        assert!(tracker.join("DAN~", "#a", None, start + SETTLE));
+    }
+
+    #[test]
+    fn batches_end_with_the_batch() {
+        let start = Instant::now();
+        let mut tracker = NetsplitTracker::new();
+        let servers = channels(&["hub.net", "leaf.net"]);
+        tracker.start_batch("s1", "netsplit", &servers, start);
+        // the reason doesn't matter inside the batch
+        assert!(tracker.quit("alice", Some("*.net *.split"), Some("s1"), &channels(&["#a"]), start));
+        assert!(!tracker.quit("bob", Some("bye"), Some("other"), &channels(&["#a"]), start));
+        assert!(tracker.flush(start + Duration::from_secs(60)).is_empty());
+        match tracker.end_batch("s1").as_slice() {
+            [NetChange::Split(group)] => assert_eq!(group.nicks, vec!["alice"]),
+            other => panic!("{:?}", other),
+        }
+
+        tracker.start_batch("j1", "NETJOIN", &servers, start);
+        assert!(tracker.join("alice", "#a", Some("j1"), start));
+        assert!(tracker.join("zoe", "#a", Some("j1"), start));
+        match tracker.end_batch("j1").as_slice() {
+            [NetChange::Join(group)] => assert_eq!(group.nicks, vec!["alice", "zoe"]),
+            other => panic!("{:?}", other),
+        }
+        assert!(tracker.end_batch("j1").is_empty());
+    }
+}
@@ -0,0 +1,209 @@
+use casemap::CaseMapping;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct NickState {
+    current: Option<String>,
+    preferred: String,
+    alternates: Vec<String>,
+    suffix: String,
+    max_suffixes: usize,
+    rejected: usize,
+    auto_reclaim: bool,
+    nick_len: Option<usize>,
+    #[serde(skip)]
+    case_mapping: CaseMapping,
+}
+
+impl Default for NickState {
+    fn default() -> Self {
+        NickState::new("")
+    }
+}
+
+impl NickState {
+    pub fn new(preferred: &str) -> NickState {
+        NickState {
+            current: None,
+            preferred: String::from(preferred),
+            alternates: Vec::new(),
+            suffix: String::from("_"),
+            max_suffixes: 3,
+            rejected: 0,
+            auto_reclaim: true,
+            nick_len: None,
+            case_mapping: CaseMapping::default(),
+        }
+    }
+
+    pub fn with_alternates(preferred: &str, alternates: Vec<String>) -> NickState {
+        NickState {
+            alternates,
+            ..NickState::new(preferred)
+        }
+    }
+
+    pub fn set_suffix(&mut self, suffix: &str, max_suffixes: usize) {
+        self.suffix = String::from(suffix);
+        self.max_suffixes = max_suffixes;
+    }
+
+    pub fn set_auto_reclaim(&mut self, auto_reclaim: bool) {
+        self.auto_reclaim = auto_reclaim;
+    }
+
+    pub fn set_case_mapping(&mut self, case_mapping: CaseMapping) {
+        self.case_mapping = case_mapping;
+    }
+
+    // the server's NICKLEN, None until it has told us
+    pub fn set_nick_len(&mut self, nick_len: Option<usize>) {
+        self.nick_len = nick_len;
+    }
+
+    pub fn preferred(&self) -> &str {
+        &self.preferred
+    }
+
+    pub fn current(&self) -> Option<&str> {
+        self.current.as_deref()
+    }
+
+    pub fn set_current(&mut self, nick: &str) {
+        self.current = Some(String::from(nick));
+    }
+
+    pub fn clear_current(&mut self) {
+        self.current = None;
+        self.rejected = 0;
+    }
+
+    pub fn is_me(&self, nick: &str) -> bool {
+        match self.current {
+            Some(ref me) => self.case_mapping.eq(me, nick),
+            None => false,
+        }
+    }
+
+    pub fn has_preferred(&self) -> bool {
+        self.is_me(&self.preferred)
+    }
+
+    // the preferred nick just became free and we are not using it
+    pub fn should_reclaim(&self, freed: &str) -> bool {
+        self.auto_reclaim
+            && self.current.is_some()
+            && !self.has_preferred()
+            && self.case_mapping.eq(freed, &self.preferred)
+    }
+
+    // every nick we are willing to use after the preferred one, in order.
+    // Anything over NICKLEN is cut short, for suffixed nicks it's the
+    // preferred part that gets cut so the suffix still makes it different
+    pub fn candidates(&self) -> Vec<String> {
+        let alternates = self.alternates.iter().map(|nick| String::from(self.fit(nick, 0)));
+        let suffixed = (1..=self.max_suffixes).filter_map(|count| {
+            let suffix = self.suffix.repeat(count);
+            match self.fit(&self.preferred, suffix.len()) {
+                "" => None,
+                nick => Some(format!("{}{}", nick, suffix)),
+            }
+        });
+        let mut ret: Vec<String> = Vec::new();
+        for nick in alternates.chain(suffixed) {
+            let taken = self.case_mapping.eq(&nick, &self.preferred) || ret.iter().any(|n| self.case_mapping.eq(n, &nick));
+            if !nick.is_empty() && !taken {
+                ret.push(nick);
+            }
+        }
+        ret
+    }
+
+    // as much of the nick as fits in NICKLEN with room left over for `keep` more bytes
+    fn fit<'a>(&self, nick: &'a str, keep: usize) -> &'a str {
+        let room = match self.nick_len {
+            Some(len) => len.saturating_sub(keep),
+            None => return nick,
+        };
+        let end = nick.char_indices().map(|(i, c)| i + c.len_utf8()).take_while(|end| *end <= room).last().unwrap_or(0);
+        &nick[..end]
+    }
+
+    // record that the server refused the last nick we asked for and
+    // return the candidate that will be tried next, if any are left
+    pub fn rejected(&mut self) -> Option<String> {
+        self.rejected += 1;
+        self.candidates().into_iter().nth(self.rejected - 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nicks(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| String::from(*n)).collect()
+    }
+
+    #[test]
+    fn alternates_then_suffixes() {
+        let nick = NickState::with_alternates("fruitbot", nicks(&["fruit_bot", "fbot"]));
+        assert_eq!(nick.candidates(), nicks(&["fruit_bot", "fbot", "fruitbot_", "fruitbot__", "fruitbot___"]));
+    }
+
+    #[test]
+    fn custom_suffix() {
+        let mut nick = NickState::new("bot");
+        nick.set_suffix("-", 2);
+        assert_eq!(nick.candidates(), nicks(&["bot-", "bot--"]));
+        nick.set_suffix("_", 0);
+        assert!(nick.candidates().is_empty());
+    }
+
+    #[test]
+    fn truncated_to_nicklen() {
+        let mut nick = NickState::with_alternates("fruitbot", nicks(&["fruit_bot_9000", "fruitbot"]));
+        nick.set_nick_len(Some(9));
+        // the preferred part is cut so the suffix always fits, and nothing
+        // that ends up the same as an earlier nick is tried twice
+        assert_eq!(nick.candidates(), nicks(&["fruit_bot", "fruitbot_", "fruitbo__", "fruitb___"]));
+        nick.set_nick_len(Some(8));
+        assert_eq!(nick.candidates(), nicks(&["fruit_bo", "fruitbo_", "fruitb__", "fruit___"]));
+        nick.set_nick_len(Some(2));
+        assert_eq!(nick.candidates(), nicks(&["fr", "f_"]));
+        nick.set_nick_len(None);
+        assert_eq!(nick.candidates()[0], "fruit_bot_9000");
+    }
+
+    #[test]
+    fn duplicates_use_the_case_mapping() {
+        let nick = NickState::with_alternates("Bot[1]", nicks(&["bot{1}", "Other", "OTHER"]));
+        assert_eq!(nick.candidates(), nicks(&["Other", "Bot[1]_", "Bot[1]__", "Bot[1]___"]));
+    }
+
+    #[test]
+    fn rejections_walk_the_candidates() {
+        let mut nick = NickState::with_alternates("bot", nicks(&["alt"]));
+        nick.set_suffix("_", 1);
+        assert_eq!(nick.rejected(), Some(String::from("alt")));
+        assert_eq!(nick.rejected(), Some(String::from("bot_")));
+        assert_eq!(nick.rejected(), None);
+        // a new connection starts from the top again
+        nick.clear_current();
+        assert_eq!(nick.rejected(), Some(String::from("alt")));
+    }
+
+    #[test]
+    fn reclaim() {
+        let mut nick = NickState::new("Bot");
+        assert!(!nick.should_reclaim("bot"));
+        nick.set_current("bot_");
+        assert!(nick.should_reclaim("BOT"));
+        assert!(!nick.should_reclaim("someone"));
+        nick.set_current("bot");
+        assert!(nick.has_preferred());
+        assert!(!nick.should_reclaim("bot"));
+        nick.set_current("bot_");
+        nick.set_auto_reclaim(false);
+        assert!(!nick.should_reclaim("bot"));
+    }
+}
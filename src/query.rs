@@ -0,0 +1,32 @@
+use channel::ChannelMessage;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Query {
+    nick: String,
+    messages: Vec<ChannelMessage>,
+}
+
+impl Query {
+    pub fn with_nick(nick: &str) -> Query {
+        Query {
+            nick: String::from(nick),
+            messages: Vec::new(),
+        }
+    }
+
+    pub fn nick(&self) -> &str {
+        &self.nick
+    }
+
+    pub fn rename(&mut self, nick: &str) {
+        self.nick = String::from(nick);
+    }
+
+    pub fn add_message(&mut self, msg: ChannelMessage) {
+        self.messages.push(msg);
+    }
+
+    pub fn messages(&self) -> &[ChannelMessage] {
+        &self.messages
+    }
+}
@@ -0,0 +1,61 @@
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const DEFAULT_BASE: Duration = Duration::from_secs(2);
+const DEFAULT_MAX: Duration = Duration::from_secs(300);
+
+// exponential backoff between connection attempts. Each delay is picked
+// at random between half and all of the current step so a lot of clients
+// dropped by the same netsplit don't all come back at once
+#[derive(Debug, Clone)]
+pub struct Backoff {
+    base: Duration,
+    max: Duration,
+    attempt: u32,
+    seed: u64,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Backoff::new(DEFAULT_BASE, DEFAULT_MAX)
+    }
+}
+
+impl Backoff {
+    pub fn new(base: Duration, max: Duration) -> Backoff {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos() as u64).unwrap_or(0);
+        Backoff {
+            base,
+            max,
+            attempt: 0,
+            // xorshift gets stuck on 0
+            seed: seed | 1,
+        }
+    }
+
+    pub fn attempt(&self) -> u32 {
+        self.attempt
+    }
+
+    // the connection worked, the next drop starts over from the base delay
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    pub fn next_delay(&mut self) -> Duration {
+        let step = self.base
+            .checked_mul(2u32.saturating_pow(self.attempt.min(31)))
+            .unwrap_or(self.max)
+            .min(self.max);
+        self.attempt = self.attempt.saturating_add(1);
+        let half = step / 2;
+        half + half.mul_f64(self.random())
+    }
+
+    // a float in [0, 1), good enough for spreading reconnects out
+    fn random(&mut self) -> f64 {
+        self.seed ^= self.seed << 13;
+        self.seed ^= self.seed >> 7;
+        self.seed ^= self.seed << 17;
+        (self.seed >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
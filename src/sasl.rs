@@ -0,0 +1,242 @@
+use std::env;
+use std::time::{Duration, Instant};
+
+use base64;
+use irc::proto::Command;
+
+// AUTHENTICATE payloads are sent in pieces of at most this many bytes
+const CHUNK_LEN: usize = 400;
+
+#[derive(Clone)]
+pub enum SaslCredentials {
+    Plain {
+        user: String,
+        password: String,
+    },
+    // the client certificate given to the connection does the talking
+    External,
+}
+
+impl SaslCredentials {
+    pub fn plain(user: &str, password: &str) -> SaslCredentials {
+        SaslCredentials::Plain {
+            user: String::from(user),
+            password: String::from(password),
+        }
+    }
+
+    // SASL_MECHANISM=EXTERNAL or SASL_USERNAME and SASL_PASSWORD for PLAIN
+    pub fn from_env() -> Option<SaslCredentials> {
+        match env::var("SASL_MECHANISM") {
+            Ok(ref mech) if mech.eq_ignore_ascii_case("EXTERNAL") => Some(SaslCredentials::External),
+            _ => {
+                let user = env::var("SASL_USERNAME").ok()?;
+                let password = env::var("SASL_PASSWORD").ok()?;
+                Some(SaslCredentials::plain(&user, &password))
+            },
+        }
+    }
+
+    pub fn mechanism(&self) -> &'static str {
+        match *self {
+            SaslCredentials::Plain { .. } => "PLAIN",
+            SaslCredentials::External => "EXTERNAL",
+        }
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        match *self {
+            // authzid \0 authcid \0 password, leaving authzid empty logs in as authcid
+            SaslCredentials::Plain { ref user, ref password } => format!("\0{}\0{}", user, password).into_bytes(),
+            SaslCredentials::External => Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SaslState {
+    Idle,
+    Started,
+    Sent,
+    Succeeded,
+    Failed,
+}
+
+#[derive(Clone, Serialize)]
+pub struct Sasl {
+    #[serde(skip)]
+    credentials: SaslCredentials,
+    mechanism: &'static str,
+    state: SaslState,
+    account: Option<String>,
+    #[serde(skip)]
+    started: Option<Instant>,
+    #[serde(skip)]
+    timeout: Duration,
+}
+
+impl Sasl {
+    pub fn new(credentials: SaslCredentials) -> Sasl {
+        Sasl {
+            mechanism: credentials.mechanism(),
+            credentials,
+            state: SaslState::Idle,
+            account: None,
+            started: None,
+            timeout: Duration::from_secs(30),
+        }
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    pub fn state(&self) -> SaslState {
+        self.state
+    }
+
+    pub fn account(&self) -> Option<&str> {
+        self.account.as_deref()
+    }
+
+    pub fn in_progress(&self) -> bool {
+        matches!(self.state, SaslState::Started | SaslState::Sent)
+    }
+
+    // the server lists its mechanisms as the value of the sasl capability,
+    // older servers don't list anything and we just have to try
+    pub fn supported_by(&self, mechanisms: Option<&str>) -> bool {
+        match mechanisms {
+            Some(mechanisms) => mechanisms.split(',').any(|m| m.eq_ignore_ascii_case(self.mechanism)),
+            None => true,
+        }
+    }
+
+    pub fn start(&mut self, now: Instant) -> Command {
+        self.state = SaslState::Started;
+        self.started = Some(now);
+        Command::AUTHENTICATE(String::from(self.mechanism))
+    }
+
+    // answer an AUTHENTICATE from the server, both of our mechanisms
+    // only ever see the empty "+" challenge
+    pub fn respond(&mut self, challenge: &str) -> Vec<Command> {
+        if self.state != SaslState::Started || challenge != "+" {
+            return Vec::new();
+        }
+        self.state = SaslState::Sent;
+        chunks(&self.credentials.payload()).into_iter().map(Command::AUTHENTICATE).collect()
+    }
+
+    pub fn timed_out(&self, now: Instant) -> bool {
+        match self.started {
+            Some(started) => self.in_progress() && now.duration_since(started) >= self.timeout,
+            None => false,
+        }
+    }
+
+    pub fn abort(&mut self) -> Command {
+        self.state = SaslState::Failed;
+        Command::AUTHENTICATE(String::from("*"))
+    }
+
+    pub fn succeeded(&mut self) {
+        self.state = SaslState::Succeeded;
+    }
+
+    pub fn failed(&mut self) {
+        self.state = SaslState::Failed;
+    }
+
+    pub fn logged_in(&mut self, account: &str) {
+        self.account = Some(String::from(account));
+    }
+
+    pub fn logged_out(&mut self) {
+        self.account = None;
+    }
+
+    // we have to log in again on every connection
+    pub fn reset(&mut self) {
+        self.state = SaslState::Idle;
+        self.account = None;
+        self.started = None;
+    }
+}
+
+pub fn chunks(payload: &[u8]) -> Vec<String> {
+    let encoded = base64::encode(payload);
+    let mut ret: Vec<String> = encoded.as_bytes()
+        .chunks(CHUNK_LEN)
+        .map(|c| String::from_utf8_lossy(c).into_owned())
+        .collect();
+    // a payload that is empty or ends exactly on a chunk boundary needs a
+    // "+" so the server knows nothing else is coming
+    if encoded.len().is_multiple_of(CHUNK_LEN) {
+        ret.push(String::from("+"));
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn authenticate(commands: Vec<Command>) -> Vec<String> {
+        commands.into_iter().map(|c| match c {
+            Command::AUTHENTICATE(payload) => payload,
+            _ => panic!("expected AUTHENTICATE"),
+        }).collect()
+    }
+
+    #[test]
+    fn empty_payload() {
+        assert_eq!(chunks(b""), vec!["+"]);
+    }
+
+    #[test]
+    fn short_payload() {
+        assert_eq!(chunks(b"\0bob\0hunter2"), vec!["AGJvYgBodW50ZXIy"]);
+    }
+
+    #[test]
+    fn long_payloads() {
+        // 300 bytes is exactly one chunk once encoded
+        let exact = chunks(&[b'a'; 300]);
+        assert_eq!(exact.len(), 2);
+        assert_eq!(exact[0].len(), CHUNK_LEN);
+        assert_eq!(exact[1], "+");
+        let longer = chunks(&[b'a'; 301]);
+        assert_eq!(longer.len(), 2);
+        assert_eq!(longer[0].len(), CHUNK_LEN);
+        assert_eq!(longer[1].len(), 4);
+        assert_eq!(longer.concat(), base64::encode(&[b'a'; 301][..]));
+    }
+
+    #[test]
+    fn plain_exchange() {
+        let mut sasl = Sasl::new(SaslCredentials::plain("bob", "hunter2"));
+        assert!(sasl.supported_by(None));
+        assert!(sasl.supported_by(Some("EXTERNAL,plain")));
+        assert!(!sasl.supported_by(Some("EXTERNAL")));
+        // nothing to answer before we start
+        assert!(sasl.respond("+").is_empty());
+        let now = Instant::now();
+        assert_eq!(authenticate(vec![sasl.start(now)]), vec!["PLAIN"]);
+        assert!(sasl.respond("something").is_empty());
+        assert_eq!(authenticate(sasl.respond("+")), vec!["AGJvYgBodW50ZXIy"]);
+        assert_eq!(sasl.state(), SaslState::Sent);
+        assert!(!sasl.timed_out(now));
+        assert!(sasl.timed_out(now + Duration::from_secs(30)));
+        sasl.succeeded();
+        assert!(!sasl.timed_out(now + Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn external_exchange() {
+        let mut sasl = Sasl::new(SaslCredentials::External);
+        assert_eq!(authenticate(vec![sasl.start(Instant::now())]), vec!["EXTERNAL"]);
+        assert_eq!(authenticate(sasl.respond("+")), vec!["+"]);
+    }
+}
@@ -1,29 +1,99 @@
 use std::collections::{HashMap};
 use std::fmt::{Debug, Result, Formatter};
-use std::time::{SystemTime, UNIX_EPOCH, Duration};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use irc::client::prelude::*;
+use irc::proto::CapSubCommand;
 use serde_json::to_string;
 
 use event::Event;
+use error::ServerErrorKind;
+
+use batch::{Batch, BatchItem, BatchKind, Batches, Buffered};
+use cap::CapNegotiator;
+use casemap::{CaseKey, CaseMapping};
+use channel::{ChannelMessage, Channel, ChannelUser, Membership, MessageKind, Topic};
+use ctcp::{Ctcp, CtcpResponder};
+use directory::{ChannelDirectory, ListQuery, ListedChannel};
+use hostmask::{Hostmask, Source};
+use isupport::{self, ModeKind, NetworkInfo};
+use keepalive::{Health, Keepalive};
+use mask::Mask;
+use modes::{self, ListEntry};
+use netsplit::{NetChange, NetGroup, NetsplitTracker};
+use nick::NickState;
+use query::Query;
+use sasl::{Sasl, SaslCredentials, SaslState};
+use tags::MessageTags;
+use who::{self, WhoReply};
+use whois::{self, WhoisCache, WhoisInfo};
+
+// channels per JOIN when coming back from a reconnect
+const MAX_REJOIN: usize = 10;
+
+pub type Listener = Box<dyn Fn(Event)>;
+pub type Sender = Box<dyn Fn(Command)>;
 
-use channel::{ChannelMessage, Channel};
 #[derive(Serialize)]
 pub struct Server {
     welcome_msg: String,
     connection_status: ConnectionStatus,
     motd: String,
-    channels: HashMap<String, Channel>,
+    nick: NickState,
+    caps: CapNegotiator,
+    sasl: Option<Sasl>,
+    network: NetworkInfo,
+    case_mapping: CaseMapping,
+    channels: HashMap<CaseKey, Channel>,
+    queries: HashMap<CaseKey, Query>,
+    // can be thousands of channels, ask for it with directory()
+    #[serde(skip)]
+    directory: ChannelDirectory,
+    who_on_join: bool,
+    keepalive: Keepalive,
+    // channels and their keys to join once registered, the ones we asked
+    // for up front and then the ones we were in when the connection dropped
+    #[serde(skip)]
+    rejoin: Option<Vec<(String, Option<String>)>>,
+    // keys we joined with, a channel still joining doesn't know its key yet
+    #[serde(skip)]
+    join_keys: Vec<(String, String)>,
+    #[serde(skip)]
+    reconnecting: bool,
+    #[serde(skip)]
+    who_replies: Vec<WhoReply>,
+    // ban and exception lists asked for on channels we aren't in
+    #[serde(skip)]
+    outside_lists: HashMap<(CaseKey, char), Vec<ListEntry>>,
+    // and NAMES for them
+    #[serde(skip)]
+    outside_names: HashMap<CaseKey, Vec<String>>,
+    #[serde(skip)]
+    whois: WhoisCache,
+    #[serde(skip)]
+    netsplits: NetsplitTracker,
+    #[serde(skip)]
+    batches: Batches,
+    // true while the messages of a finished batch are being handled
+    #[serde(skip)]
+    replaying: bool,
     #[serde(skip)]
-    listener: Box<Fn(Event)>
+    listener: Listener,
+    #[serde(skip)]
+    sender: Sender,
+    #[serde(skip)]
+    ctcp_responder: Option<CtcpResponder>,
 }
 
-#[derive(Serialize)]
+#[derive(Debug, Clone, Copy, Serialize, PartialEq, Eq)]
 pub enum ConnectionStatus {
     NotConnected,
     Authenticating,
     Connected,
-    Idle
+    // registered but the server has gone quiet
+    Idle,
+    // registered but not answering our PINGs
+    Stale,
 }
 
 impl Debug for Server {
@@ -32,24 +102,279 @@ impl Debug for Server {
     }
 }
 
+impl Default for Server {
+    fn default() -> Server {
+        Server::new()
+    }
+}
+
 impl Server {
     pub fn new() -> Server {
         Server {
             welcome_msg: String::new(),
             connection_status: ConnectionStatus::NotConnected,
             motd: String::new(),
+            nick: NickState::default(),
+            caps: CapNegotiator::default(),
+            sasl: None,
+            network: NetworkInfo::default(),
+            case_mapping: CaseMapping::default(),
             channels: HashMap::new(),
+            queries: HashMap::new(),
+            directory: ChannelDirectory::new(),
+            who_on_join: true,
+            keepalive: Keepalive::default(),
+            rejoin: None,
+            join_keys: Vec::new(),
+            reconnecting: false,
+            who_replies: Vec::new(),
+            outside_lists: HashMap::new(),
+            outside_names: HashMap::new(),
+            whois: WhoisCache::new(),
+            netsplits: NetsplitTracker::new(),
+            batches: Batches::new(),
+            replaying: false,
             listener: Box::new(|_|{}),
+            sender: Box::new(|_|{}),
+            ctcp_responder: None,
         }
     }
 
-    pub fn with(listener: Box<Fn(Event)>) -> Server {
+    pub fn with(listener: Listener) -> Server {
         Server {
             welcome_msg: String::new(),
             connection_status: ConnectionStatus::NotConnected,
             motd: String::new(),
+            nick: NickState::default(),
+            caps: CapNegotiator::default(),
+            sasl: None,
+            network: NetworkInfo::default(),
+            case_mapping: CaseMapping::default(),
             channels: HashMap::new(),
-            listener
+            queries: HashMap::new(),
+            directory: ChannelDirectory::new(),
+            who_on_join: true,
+            keepalive: Keepalive::default(),
+            rejoin: None,
+            join_keys: Vec::new(),
+            reconnecting: false,
+            who_replies: Vec::new(),
+            outside_lists: HashMap::new(),
+            outside_names: HashMap::new(),
+            whois: WhoisCache::new(),
+            netsplits: NetsplitTracker::new(),
+            batches: Batches::new(),
+            replaying: false,
+            listener,
+            sender: Box::new(|_|{}),
+            ctcp_responder: None,
+        }
+    }
+
+    pub fn set_sender(&mut self, sender: Sender) {
+        self.sender = sender;
+    }
+
+    pub fn set_ctcp_responder(&mut self, mut responder: CtcpResponder) {
+        responder.set_case_mapping(self.case_mapping);
+        self.ctcp_responder = Some(responder);
+    }
+
+    pub fn set_nick_state(&mut self, nick: NickState) {
+        self.nick = nick;
+    }
+
+    pub fn nick_state(&self) -> &NickState {
+        &self.nick
+    }
+
+    pub fn set_caps(&mut self, caps: CapNegotiator) {
+        self.caps = caps;
+    }
+
+    pub fn caps(&self) -> &CapNegotiator {
+        &self.caps
+    }
+
+    pub fn has_cap(&self, cap: &str) -> bool {
+        self.caps.is_enabled(cap)
+    }
+
+    pub fn network(&self) -> &NetworkInfo {
+        &self.network
+    }
+
+    pub fn set_sasl(&mut self, credentials: SaslCredentials) {
+        self.caps.want("sasl");
+        self.caps.hold_for("sasl");
+        self.sasl = Some(Sasl::new(credentials));
+    }
+
+    pub fn sasl(&self) -> Option<&Sasl> {
+        self.sasl.as_ref()
+    }
+
+    // call regularly so a server that never answers our login doesn't
+    // leave registration hanging
+    pub fn tick(&mut self, now: Instant) {
+        let timed_out = self.sasl.as_ref().map(|s| s.timed_out(now)).unwrap_or(false);
+        if timed_out {
+            if let Some(abort) = self.sasl.as_mut().map(Sasl::abort) {
+                (self.sender)(abort);
+            }
+            self.sasl_finished();
+        }
+        self.whois.expire(now);
+        for batch in self.batches.expire(now) {
+            self.deliver(batch);
+        }
+        for change in self.netsplits.flush(now) {
+            self.net_change(change);
+        }
+        if self.is_connected() {
+            if let Some(ping) = self.keepalive.poll(now) {
+                (self.sender)(ping);
+            }
+        }
+        self.check_health(now);
+    }
+
+    fn check_health(&mut self, now: Instant) {
+        if !self.is_connected() {
+            return;
+        }
+        let status = match self.keepalive.health(now) {
+            Health::Ok => ConnectionStatus::Connected,
+            Health::Idle => ConnectionStatus::Idle,
+            Health::Stale | Health::TimedOut => ConnectionStatus::Stale,
+        };
+        self.set_connection_status(status);
+    }
+
+    // the server has stopped answering for long enough that it is better
+    // to drop the connection and start again
+    pub fn should_reconnect(&self, now: Instant) -> bool {
+        self.is_connected() && self.keepalive.health(now) == Health::TimedOut
+    }
+
+    pub fn set_keepalive(&mut self, keepalive: Keepalive) {
+        self.keepalive = keepalive;
+    }
+
+    pub fn lag(&self) -> Option<Duration> {
+        self.keepalive.lag()
+    }
+
+    fn set_connection_status(&mut self, status: ConnectionStatus) {
+        if self.connection_status != status {
+            self.connection_status = status;
+            (self.listener)(Event::Status(status))
+        }
+    }
+
+    // everything tied to the old connection is dropped, the channels we
+    // were in are joined again when the next one is registered
+    pub fn disconnected(&mut self, reason: Option<String>) {
+        // a JOIN the server never answered is still wanted, or a second drop
+        // before it is confirmed would lose the channel
+        if self.rejoin.is_none() {
+            let channels = self.channels.values()
+                .filter(|ch| ch.membership().is_present() || ch.membership() == Membership::Joining)
+                .map(|ch| {
+                    let key = ch.modes().key().or_else(|| self.join_key(ch.name()));
+                    (String::from(ch.name()), key.map(String::from))
+                })
+                .collect();
+            self.rejoin = Some(channels);
+        }
+        self.reconnecting = true;
+        for ch in self.channels.values_mut() {
+            if ch.membership().is_present() {
+                ch.set_membership(Membership::Joining);
+            }
+        }
+        self.welcome_msg.clear();
+        self.motd.clear();
+        self.set_connection_status(ConnectionStatus::NotConnected);
+        self.keepalive.reset();
+        self.nick.clear_current();
+        self.caps.reset();
+        if let Some(ref mut sasl) = self.sasl {
+            sasl.reset();
+        }
+        self.network = NetworkInfo::default();
+        self.who_replies.clear();
+        self.outside_lists.clear();
+        self.outside_names.clear();
+        self.whois.clear();
+        self.netsplits.clear();
+        self.batches.clear();
+        self.directory.start();
+        (self.listener)(Event::Disconnected {
+            reason,
+        })
+    }
+
+    pub fn is_connected(&self) -> bool {
+        matches!(self.connection_status, ConnectionStatus::Connected | ConnectionStatus::Idle | ConnectionStatus::Stale)
+    }
+
+    pub fn reconnecting(&mut self, attempt: u32, delay: Duration) {
+        (self.listener)(Event::Reconnecting {
+            attempt,
+            delay: delay.as_secs(),
+        })
+    }
+
+    // the rejoin after every reconnect is the only place channels are
+    // joined from, so one we left or were kicked from stays that way
+    pub fn join_on_connect(&mut self, channel: &str, key: Option<&str>) {
+        self.rejoin.get_or_insert_with(Vec::new).push((String::from(channel), key.map(String::from)));
+    }
+
+    fn rejoin(&mut self) {
+        let reconnecting = self.reconnecting;
+        self.reconnecting = false;
+        let mut channels = match self.rejoin.take() {
+            Some(channels) => channels,
+            None => return,
+        };
+        // keys are matched to channels by position so keyed ones go first
+        channels.sort_by_key(|c| c.1.is_none());
+        // so a failed JOIN can be told apart from errors about channels we are in
+        for (name, key) in &channels {
+            if let Some(ref key) = *key {
+                self.set_join_key(name, key);
+            }
+            if self.channel(name).map(|ch| ch.membership() != Membership::Joining).unwrap_or(true) {
+                self.set_own_membership(name, Membership::Joining);
+            }
+        }
+        for chunk in channels.chunks(MAX_REJOIN) {
+            let names: Vec<&str> = chunk.iter().map(|c| c.0.as_str()).collect();
+            let keys: Vec<&str> = chunk.iter().filter_map(|c| c.1.as_deref()).collect();
+            let keys = if keys.is_empty() { None } else { Some(keys.join(",")) };
+            (self.sender)(Command::JOIN(names.join(","), keys, None));
+        }
+        if reconnecting {
+            (self.listener)(Event::Reconnected {
+                channels: channels.into_iter().map(|c| c.0).collect(),
+            })
+        }
+    }
+
+    // start registration, capability negotiation has to begin before NICK/USER
+    // so the server holds off on the welcome until we send CAP END
+    pub fn register(&mut self, user: &str, real_name: &str) {
+        let ls = self.caps.start();
+        (self.sender)(ls);
+        (self.sender)(Command::NICK(String::from(self.nick.preferred())));
+        (self.sender)(Command::USER(String::from(user), String::from("0"), String::from(real_name)));
+    }
+
+    pub fn reclaim_nick(&mut self) {
+        if !self.nick.has_preferred() {
+            (self.sender)(Command::NICK(String::from(self.nick.preferred())));
         }
     }
 
@@ -74,275 +399,1234 @@ impl Server {
         self.motd.clone()
     }
 
+    fn key(&self, name: &str) -> CaseKey {
+        self.case_mapping.key(name)
+    }
+
+    pub fn channel(&self, channel: &str) -> Option<&Channel> {
+        self.channels.get(&self.key(channel))
+    }
+
+    fn channel_mut(&mut self, channel: &str) -> &mut Channel {
+        let key = self.key(channel);
+        let case_mapping = self.case_mapping;
+        let ch = self.channels.entry(key).or_insert_with(|| Channel::with_name(channel));
+        ch.set_case_mapping(case_mapping);
+        ch.set_name(channel);
+        ch
+    }
+
+    // names that were the same under the old rules might not be any more,
+    // so everything gets filed again under the new keys
+    fn set_case_mapping(&mut self, case_mapping: CaseMapping) {
+        if self.case_mapping == case_mapping {
+            return;
+        }
+        self.case_mapping = case_mapping;
+        self.nick.set_case_mapping(case_mapping);
+        self.netsplits.set_case_mapping(case_mapping);
+        if let Some(ref mut responder) = self.ctcp_responder {
+            responder.set_case_mapping(case_mapping);
+        }
+        let channels = self.channels.drain().map(|(_, mut ch)| {
+            ch.set_case_mapping(case_mapping);
+            (case_mapping.key(ch.name()), ch)
+        }).collect();
+        self.channels = channels;
+        let queries = self.queries.drain().map(|(_, q)| (case_mapping.key(q.nick()), q)).collect();
+        self.queries = queries;
+        self.whois.clear();
+    }
+
     pub fn add_users(&mut self, channel: &str, names: &str) {
-        let ch = self.channels.entry(String::from(channel)).or_insert(Channel::new());
-        ch.add_users(names);
+        let prefixes = self.network.prefixes().to_vec();
+        let multi_prefix = self.has_cap("multi-prefix");
+        let key = self.key(channel);
+        if let Some(ch) = self.channels.get_mut(&key) {
+            ch.add_users(names, &prefixes, multi_prefix);
+            return;
+        }
+        // a channel we aren't in, the nicks are passed on but not kept
+        let nicks = names.split(' ')
+            .map(|entry| entry.trim_start_matches(|c| prefixes.iter().any(|p| p.1 == c)))
+            .map(|entry| Hostmask::parse(entry).nick)
+            .filter(|nick| !nick.is_empty());
+        self.outside_names.entry(key).or_default().extend(nicks);
     }
 
     pub fn add_ch_topic(&mut self, channel: &str, topic: &str) {
-        let ch = self.channels.entry(String::from(channel)).or_insert(Channel::new());
-        ch.set_topic(topic);
+        self.change_topic(channel, topic, None, None);
+    }
+
+    // an empty topic is how servers say it was removed
+    fn change_topic(&mut self, channel: &str, topic: &str, by: Option<&str>, at: Option<u64>) {
+        let key = self.key(channel);
+        let (name, old) = match self.channels.get_mut(&key) {
+            Some(ch) => (String::from(ch.name()), ch.set_topic(topic, by, at)),
+            // a channel we aren't in, the topic is passed on but not kept
+            None => (String::from(channel), Some(Topic::default())),
+        };
+        if let Some(old) = old {
+            let text = |t: &str| if t.is_empty() { None } else { Some(String::from(t)) };
+            (self.listener)(Event::Topic {
+                channel: name,
+                by: by.map(String::from),
+                old: text(&old.text),
+                new: text(topic),
+                at,
+            })
+        }
+    }
+
+    pub fn is_me(&self, nick: &str) -> bool {
+        self.nick.is_me(nick)
+    }
+
+    pub fn join(&mut self, channel: &str, key: Option<&str>) {
+        if let Some(key) = key {
+            self.set_join_key(channel, key);
+        }
+        self.set_own_membership(channel, Membership::Joining);
+        (self.sender)(Command::JOIN(String::from(channel), key.map(String::from), None));
+    }
+
+    fn join_key(&self, channel: &str) -> Option<&str> {
+        self.join_keys.iter().find(|(name, _)| self.case_mapping.eq(name, channel)).map(|(_, key)| key.as_str())
+    }
+
+    fn set_join_key(&mut self, channel: &str, key: &str) {
+        let case_mapping = self.case_mapping;
+        self.join_keys.retain(|(name, _)| !case_mapping.eq(name, channel));
+        self.join_keys.push((String::from(channel), String::from(key)));
+    }
+
+    pub fn part(&mut self, channel: &str, reason: Option<&str>) {
+        (self.sender)(Command::PART(String::from(channel), reason.map(String::from)));
+    }
+
+    // long messages are split so nothing gets cut off by the server
+    pub fn send_message(&mut self, target: &str, text: &str) {
+        for line in text.lines() {
+            for part in self.network.split_text("PRIVMSG", target, line) {
+                (self.sender)(Command::PRIVMSG(String::from(target), part));
+            }
+        }
+    }
+
+    pub fn set_topic(&mut self, channel: &str, topic: &str) {
+        let mut end = topic.len().min(self.network.topic_len().unwrap_or(topic.len()));
+        while !topic.is_char_boundary(end) {
+            end -= 1;
+        }
+        (self.sender)(Command::TOPIC(String::from(channel), Some(String::from(&topic[..end]))));
+    }
+
+    // the server only takes so many modes with arguments in one MODE
+    pub fn set_modes(&mut self, channel: &str, modes: Vec<Mode<ChannelMode>>) {
+        let max = self.network.max_modes().unwrap_or(modes.len()).max(1);
+        for chunk in modes.chunks(max) {
+            (self.sender)(Command::ChannelMODE(String::from(channel), chunk.to_vec()));
+        }
+    }
+
+    fn set_own_membership(&mut self, channel: &str, state: Membership) {
+        let nick = String::from(self.nick.current().unwrap_or_default());
+        self.channel_mut(channel).set_membership(state);
+        (self.listener)(Event::MembershipChanged {
+            channel: String::from(channel),
+            nick,
+            state,
+        });
+    }
+
+    fn user_joined(&mut self, channel: &str, mask: &Hostmask) {
+        let username = mask.nick();
+        if self.is_me(username) {
+            let case_mapping = self.case_mapping;
+            self.join_keys.retain(|(name, _)| !case_mapping.eq(name, channel));
+            self.channel_mut(channel).prune_users();
+            self.set_own_membership(channel, Membership::Joined);
+            // ask for the modes, the server only tells us about changes from here on
+            (self.sender)(Command::Raw(String::from("MODE"), vec![String::from(channel)], None));
+            // and for the user, host and account of everyone already here
+            if self.who_on_join {
+                self.who(channel);
+            }
+        }
+        let ch = self.channel_mut(channel);
+        if ch.join_user(mask) {
+            let users = ch.users();
+            (self.listener)(Event::MembershipChanged {
+                channel: String::from(channel),
+                nick: String::from(username),
+                state: Membership::Joined,
+            });
+            (self.listener)(Event::NewUsers(String::from(channel), users));
+        }
+    }
+
+    fn user_left(&mut self, channel: &str, username: &str, state: Membership) {
+        if self.is_me(username) {
+            if self.channel(channel).is_some() {
+                self.set_own_membership(channel, state);
+            }
+            return;
+        }
+        let key = self.key(channel);
+        if let Some(ch) = self.channels.get_mut(&key) {
+            if ch.part_user(username, state) {
+                (self.listener)(Event::MembershipChanged {
+                    channel: String::from(channel),
+                    nick: String::from(username),
+                    state,
+                });
+                (self.listener)(Event::NewUsers(String::from(channel), ch.users()));
+            }
+        }
     }
 
     pub fn remove_user(&mut self, username: &str) {
-        self.channels = self.channels.clone().into_iter().map(|mut e| {
-            if e.1.remove_user(username) {
-                (self.listener)(Event::NewUsers(e.0.clone(), e.1.users()));
+        let channels: Vec<String> = self.channels.values().map(|ch| String::from(ch.name())).collect();
+        for channel in channels {
+            self.user_left(&channel, username, Membership::Parted);
+        }
+    }
+
+    // a quit that is part of a netsplit, the users go quietly and are
+    // reported all at once when the split is over
+    fn split_quit(&mut self, username: &str, reason: Option<&str>, batch: Option<&str>) -> bool {
+        let channels: Vec<String> = self.channels.values()
+            .filter(|ch| ch.user_membership(username).map(Membership::is_present).unwrap_or(false))
+            .map(|ch| String::from(ch.name()))
+            .collect();
+        if !self.netsplits.quit(username, reason, batch, &channels, Instant::now()) {
+            return false;
+        }
+        for ch in self.channels.values_mut() {
+            ch.part_user(username, Membership::Parted);
+        }
+        true
+    }
+
+    fn net_change(&mut self, change: NetChange) {
+        let (group, split) = match change {
+            NetChange::Split(group) => (group, true),
+            NetChange::Join(group) => (group, false),
+        };
+        let NetGroup { servers, nicks, channels } = group;
+        let channels: Vec<String> = channels.into_iter().collect();
+        for channel in &channels {
+            if let Some(ch) = self.channel(channel) {
+                (self.listener)(Event::NewUsers(String::from(ch.name()), ch.users()));
             }
-            e
-        }).collect();
+        }
+        if split {
+            (self.listener)(Event::Netsplit {
+                servers,
+                nicks,
+                channels,
+            })
+        } else {
+            (self.listener)(Event::Netjoin {
+                servers,
+                nicks,
+                channels,
+            })
+        }
+    }
+
+    fn deliver(&mut self, batch: Batch) {
+        let replaying = self.replaying;
+        self.replaying = true;
+        match batch.kind {
+            BatchKind::Netsplit | BatchKind::Netjoin => {
+                self.netsplits.start_batch(&batch.reference, batch.kind.as_str(), &batch.params, Instant::now());
+                self.replay(batch.items);
+                for change in self.netsplits.end_batch(&batch.reference) {
+                    self.net_change(change);
+                }
+            },
+            // history only ever gets shown, none of it changes what we know now
+            BatchKind::ChatHistory => {
+                let messages = batch.items.into_iter().filter_map(|item| match item {
+                    BatchItem::Message(msg) => Self::history_message(msg),
+                    BatchItem::Batch(ref inner) if inner.kind == BatchKind::Multiline => {
+                        Self::multiline(inner).map(|(prefix, _, text, kind)| {
+                            ChannelMessage::new(Self::short_name(prefix), text, kind).with_tags(&inner.tags)
+                        })
+                    },
+                    BatchItem::Batch(_) => None,
+                }).collect();
+                (self.listener)(Event::ChatHistory {
+                    target: batch.params.first().cloned(),
+                    messages,
+                })
+            },
+            BatchKind::Multiline => {
+                if let Some((prefix, target, text, kind)) = Self::multiline(&batch) {
+                    self.new_message(prefix, target, text, kind, &batch.tags);
+                }
+            },
+            BatchKind::LabeledResponse => {
+                let lines = batch.items.len();
+                let label = batch.tags.label().map(String::from);
+                self.replay(batch.items);
+                (self.listener)(Event::LabeledResponse {
+                    label,
+                    lines,
+                })
+            },
+            // batch types we don't know about are handled like any other lines
+            BatchKind::Other(ref kind) => {
+                let kind = Some(kind.clone());
+                self.replay(batch.items);
+                (self.listener)(Event::Batch {
+                    reference: batch.reference,
+                    kind,
+                    params: batch.params,
+                })
+            },
+        }
+        self.replaying = replaying;
+    }
+
+    fn replay(&mut self, items: Vec<BatchItem>) {
+        for item in items {
+            match item {
+                BatchItem::Message(msg) => self.handle_message(msg),
+                BatchItem::Batch(batch) => self.deliver(batch),
+            }
+        }
+    }
+
+    fn history_message(msg: Message) -> Option<ChannelMessage> {
+        let tags = MessageTags::from(msg.tags);
+        let (text, kind) = match msg.command {
+            Command::PRIVMSG(_, text) => (text, MessageKind::Message),
+            Command::NOTICE(_, text) => (text, MessageKind::Notice),
+            _ => return None,
+        };
+        let (content, kind) = match Ctcp::parse(&text) {
+            Some(ref ctcp) if ctcp.is_action() => (ctcp.params.clone().unwrap_or_default(), MessageKind::Action),
+            Some(_) => return None,
+            None => (text, kind),
+        };
+        Some(ChannelMessage::new(Self::short_name(msg.prefix), content, kind).with_tags(&tags))
+    }
+
+    // the lines of a draft/multiline batch put back together, each one is a
+    // new line unless it is tagged to carry on from the last
+    fn multiline(batch: &Batch) -> Option<(Option<String>, String, String, MessageKind)> {
+        let target = batch.params.first()?.clone();
+        let mut prefix = None;
+        let mut text = String::new();
+        let mut kind = MessageKind::Message;
+        for msg in batch.messages() {
+            let line = match msg.command {
+                Command::PRIVMSG(_, ref line) => line,
+                Command::NOTICE(_, ref line) => {
+                    kind = MessageKind::Notice;
+                    line
+                },
+                _ => continue,
+            };
+            let concat = msg.tags.as_ref()
+                .map(|tags| tags.iter().any(|t| t.0 == "draft/multiline-concat"))
+                .unwrap_or(false);
+            if prefix.is_some() && !concat {
+                text.push('\n');
+            }
+            text.push_str(line);
+            if prefix.is_none() {
+                prefix = msg.prefix.clone();
+            }
+        }
+        // nobody said anything
+        prefix.map(|prefix| (Some(prefix), target, text, kind))
+    }
+
+    fn update_host(&mut self, mask: &Hostmask) {
+        for ch in self.channels.values_mut() {
+            ch.set_user_host(mask);
+        }
+    }
+
+    // the user and host of anyone we share a channel with, as far as we know them
+    pub fn hostmask(&self, nick: &str) -> Option<Hostmask> {
+        let users = self.channels.values().filter_map(|ch| ch.user(nick));
+        let mut found = None;
+        for user in users {
+            let mask = user.hostmask();
+            if mask.is_complete() {
+                return Some(mask);
+            }
+            found = Some(mask);
+        }
+        found
+    }
+
+    // with ELIST the server does some of the filtering, either way the
+    // replies end up in the directory to be searched
+    pub fn list_channels(&mut self, query: &ListQuery) {
+        let params = self.network.get("ELIST").map(|e| query.elist_params(e)).unwrap_or_default();
+        self.directory.set_complete(params.is_empty());
+        let params = if params.is_empty() { None } else { Some(params.join(",")) };
+        (self.sender)(Command::LIST(params, None));
+    }
+
+    pub fn directory(&self) -> &ChannelDirectory {
+        &self.directory
+    }
+
+    pub fn search_channels(&self, query: &ListQuery) -> Vec<&ListedChannel> {
+        self.directory.search(query, self.case_mapping)
+    }
+
+    // WHOX when the network has it so we learn accounts too
+    pub fn who(&mut self, mask: &str) {
+        let mut args = vec![String::from(mask)];
+        if self.network.contains("WHOX") {
+            args.push(format!("%{},{}", who::WHOX_FIELDS, who::WHOX_TOKEN));
+        }
+        (self.sender)(Command::Raw(String::from("WHO"), args, None));
+    }
+
+    pub fn set_who_on_join(&mut self, who_on_join: bool) {
+        self.who_on_join = who_on_join;
+    }
+
+    fn who_reply(&mut self, reply: WhoReply) {
+        let prefixes = self.network.prefixes().to_vec();
+        let multi_prefix = self.has_cap("multi-prefix");
+        for ch in self.channels.values_mut() {
+            ch.apply_who(&reply, &prefixes, multi_prefix);
+        }
+        self.who_replies.push(reply);
+    }
+
+    // answered from the cache while it is fresh, otherwise a WHOIS is sent
+    // and the reply arrives as Event::Whois
+    pub fn whois(&mut self, nick: &str) -> Option<WhoisInfo> {
+        let key = self.key(nick);
+        if let Some(info) = self.whois.get(&key, Instant::now()) {
+            return Some(info.clone());
+        }
+        if !self.whois.is_pending(&key) {
+            self.whois.start(key, nick, Instant::now());
+            (self.sender)(Command::WHOIS(None, String::from(nick)));
+        }
+        None
+    }
+
+    pub fn set_whois_ttl(&mut self, ttl: Duration) {
+        self.whois.set_ttl(ttl);
+    }
+
+    fn whois_line(&mut self, code: u16, args: Vec<String>, suffix: Option<String>) {
+        let nick = match args.get(1) {
+            Some(nick) => nick.clone(),
+            None => return,
+        };
+        if code == whois::RPL_WHOISUSER && args.len() > 3 {
+            self.update_host(&Hostmask::new(&nick, Some(&args[2]), Some(&args[3])));
+        }
+        let key = self.key(&nick);
+        self.whois.line(key, &nick, code, &args, suffix.as_deref(), Instant::now());
+    }
+
+    fn end_whois(&mut self, args: &[String]) {
+        let key = match args.get(1) {
+            Some(nick) => self.key(nick),
+            None => return,
+        };
+        if let Some(info) = self.whois.finish(&key, Instant::now()) {
+            (self.listener)(Event::Whois(info))
+        }
+    }
+
+    pub fn query(&self, nick: &str) -> Option<&Query> {
+        self.queries.get(&self.key(nick))
+    }
+
+    pub fn close_query(&mut self, nick: &str) -> Option<Query> {
+        let key = self.key(nick);
+        self.queries.remove(&key)
     }
 
     pub fn change_nick(&mut self, old: &str, new: &str) {
-        self.remove_user(old);
-        self.channels = self.channels.clone().into_iter().map(|mut e| {
-            if e.1.add_users(new) > 0 {
-                (self.listener)(Event::NewUsers(e.0.clone(), e.1.users()));
+        if let Some(mut query) = self.close_query(old) {
+            query.rename(new);
+            let key = self.key(new);
+            self.queries.insert(key, query);
+        }
+        if self.is_me(old) {
+            self.nick.set_current(new);
+        } else if self.nick.should_reclaim(old) {
+            self.reclaim_nick();
+        }
+        for ch in self.channels.values_mut() {
+            if ch.rename_user(old, new) {
+                (self.listener)(Event::NewUsers(String::from(ch.name()), ch.users()));
             }
-            e
-        }).collect();
+        }
+    }
+
+    fn apply_chan_modes(&mut self, channel: &str, change: Vec<Mode<ChannelMode>>, by: &str, at: u64) {
+        let mut users_changed = false;
+        let network = &self.network;
+        let key = self.case_mapping.key(channel);
+        if let Some(ch) = self.channels.get_mut(&key) {
+            for mode in change {
+                let (adding, mode, arg) = match mode {
+                    Mode::Plus(mode, arg) => (true, isupport::mode_char(&mode), arg),
+                    Mode::Minus(mode, arg) => (false, isupport::mode_char(&mode), arg),
+                };
+                let kind = network.mode_kind(mode);
+                match (kind, arg) {
+                    (Some(ModeKind::Prefix), Some(ref un)) if adding => {
+                        users_changed = ch.add_privilege(un, mode, network.prefixes()) || users_changed;
+                    },
+                    (Some(ModeKind::Prefix), Some(ref un)) => {
+                        users_changed = ch.remove_privilege(un, mode) || users_changed;
+                    },
+                    (kind, arg) => {
+                        ch.apply_mode(adding, mode, arg.as_deref(), kind, by, at);
+                    },
+                }
+            }
+            if users_changed {
+                (self.listener)(Event::NewUsers(String::from(channel), ch.users()));
+            }
+        }
+    }
+
+    // ask for a list mode's entries, e.g. 'b' for bans
+    pub fn request_list(&mut self, channel: &str, mode: char) {
+        (self.sender)(Command::Raw(String::from("MODE"), vec![String::from(channel), format!("+{}", mode)], None));
+    }
+
+    // $j extbans only look at the other channel's plain bans so two
+    // channels pointing at each other can't loop
+    fn banned_in(&self, channel: &str, user: &ChannelUser) -> bool {
+        match self.channel(channel) {
+            Some(ch) => !ch.bans_for(user, self.network.extban_prefix(), &|_| false).is_empty(),
+            None => false,
+        }
+    }
+
+    // the bans, extbans included, that would keep nick!user@host out,
+    // whether or not they are in the channel now
+    pub fn bans_matching(&self, channel: &str, mask: &Hostmask, account: Option<&str>) -> Vec<ListEntry> {
+        let ch = match self.channel(channel) {
+            Some(ch) => ch,
+            None => return Vec::new(),
+        };
+        let user = ChannelUser::with_hostmask(mask, account);
+        ch.bans_for(&user, self.network.extban_prefix(), &|other| self.banned_in(other, &user))
+            .into_iter()
+            .cloned()
+            .collect()
+    }
+
+    // the bans, extbans included, keeping someone in the channel out
+    pub fn bans_for(&self, channel: &str, nick: &str) -> Vec<ListEntry> {
+        let ch = match self.channel(channel) {
+            Some(ch) => ch,
+            None => return Vec::new(),
+        };
+        match ch.user(nick) {
+            Some(user) => ch.bans_for(user, self.network.extban_prefix(), &|other| self.banned_in(other, user))
+                .into_iter()
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    // the nicks in a channel a ban mask would hit, before it is set
+    pub fn mask_hits(&self, channel: &str, mask: &str) -> Vec<String> {
+        let mask = Mask::parse(mask, self.network.extban_prefix());
+        match self.channel(channel) {
+            Some(ch) => ch.hit_by(&mask, &|other, user| self.banned_in(other, user))
+                .into_iter()
+                .map(|u| String::from(u.name()))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    pub fn mask_hits_user(&self, channel: &str, nick: &str, mask: &str) -> bool {
+        let hit = self.mask_hits(channel, mask);
+        hit.iter().any(|n| self.case_mapping.eq(n, nick))
+    }
+
+    pub fn mask_hits_me(&self, channel: &str, mask: &str) -> bool {
+        match self.nick.current() {
+            Some(me) => self.mask_hits_user(channel, me, mask),
+            None => false,
+        }
+    }
+
+    // <nick> <channel> <mask> [<set by> <set at>]
+    fn list_entry(&mut self, mode: char, args: &[String]) {
+        if args.len() < 3 {
+            return;
+        }
+        let set_at = args.get(4).and_then(|t| t.parse().ok());
+        let entry = ListEntry::new(&args[2], args.get(3).map(|s| s.as_str()), set_at);
+        let key = self.key(&args[1]);
+        match self.channels.get_mut(&key) {
+            Some(ch) => ch.list_entry(mode, entry),
+            None => self.outside_lists.entry((key, mode)).or_default().push(entry),
+        }
+    }
+
+    fn end_list(&mut self, mode: char, args: &[String]) {
+        let channel = match args.get(1) {
+            Some(channel) => channel,
+            None => return,
+        };
+        let key = self.key(channel);
+        let entries = match self.channels.get_mut(&key) {
+            Some(ch) => {
+                ch.end_list(mode);
+                ch.modes().list(mode).to_vec()
+            },
+            // passed on without being kept
+            None => self.outside_lists.remove(&(key, mode)).unwrap_or_default(),
+        };
+        (self.listener)(Event::ChannelList {
+            channel: channel.clone(),
+            mode,
+            entries,
+        })
+    }
+
+    pub fn channel_modes(&self, channel: &str) -> Option<String> {
+        self.channel(channel).map(|ch| ch.modes().render(&self.network))
     }
 
     fn short_name(long_name: Option<String>) -> String {
         match long_name {
-            Some(p) => {
-                let parts: Vec<&str> = p.split('!').collect();
-                String::from(parts[0])
-            },
+            Some(p) => String::from(Source::parse(&p).name()),
             None => String::new()
         }
     }
 
-    fn change_user_chan_mode(&mut self, channel: &str, change: Vec<Mode<ChannelMode>>) {
-        for mode in change {
-            match mode {
-                Plus(ch_mode, user_name) => {
-                    if let Some(un) = user_name {
-                        if let Some(ch) = self.channels.get(&channel) {
-                            ch.add_user_level(un, ch_mode);
-                            (self.listener)(Event::NewUsers(channel, ch.users()));
-                        }
-                    } else {
-                        (self.listener)(Event::Misc(None, String::from("ChannelMODE"), vec![channel, format!("{:?}", ch_mode), user_name.unwrap_or(String::new())], None));
-                    }
-                },
-                Minus(ch_mode, user_name) => {
-                    if let Some(un) = user_name {
-                        if let Some(ch) = self.channels.get(&channel) {
-                            ch.remove_user_level(un, ch_mode);
-                            (self.listener)(Event::NewUsers(channel, ch.users()));
-                        }
-                    } else {
-                        (self.listener)(Event::Misc(None, String::from("ChannelMODE"), vec![channel, format!("{:?}", ch_mode), user_name.unwrap_or(String::new())], None));
-                    }
-                }
-            }
+    fn hostmask_of(prefix: &Option<String>) -> Hostmask {
+        match *prefix {
+            Some(ref p) => Hostmask::parse(p),
+            None => Hostmask::new("", None, None),
+        }
+    }
+
+    fn change_user_chan_mode(&mut self, channel: &str, by: String, change: Vec<Mode<ChannelMode>>, at: u64) {
+        let changes = change.iter().map(|m| format!("{}", m)).collect();
+        self.apply_chan_modes(channel, change, &by, at);
+        (self.listener)(Event::Mode {
+            target: String::from(channel),
+            by,
+            changes,
+        });
+    }
+
+    // a line straight from the server. Parsing it into a Message keeps only
+    // the mode arguments the irc crate thinks belong to a mode, so channel
+    // MODEs are built again from the parameters on the wire
+    pub fn handle_line(&mut self, line: &str) {
+        let mut msg = match line.parse::<Message>() {
+            Ok(msg) => msg,
+            Err(e) => return (self.listener)(Event::Error(format!("Unable to parse {:?}: {}", line.trim_end(), e))),
+        };
+        if let Command::ChannelMODE(..) = msg.command {
+            let (params, trailing) = wire_params(line);
+            msg.command = Command::Raw(String::from("MODE"), params, trailing);
         }
-        
+        self.handle_message(msg)
     }
 
     #[allow(unused_variables)]
     pub fn handle_message(&mut self, msg: Message) {
-        let tags = match msg.tags {
-            Some(tags) => {
-                let tag_strs: Vec<String> = tags.into_iter().map(|e| format!("{:?}", e)).collect();
-                Some(tag_strs.join(", "))
-            },
-            _ => None
+        let now = Instant::now();
+        self.keepalive.seen(now, !matches!(msg.command, Command::PING(..) | Command::PONG(..)));
+        self.check_health(now);
+        // anything inside a batch waits for the batch to end
+        let msg = match self.batches.buffer(msg) {
+            Buffered::Live(msg) => msg,
+            Buffered::Held => return,
+            Buffered::Overflow(batch) => return self.deliver(batch),
         };
+        let tags = MessageTags::from(msg.tags);
+        let tag_str = tags.describe();
         match msg.command {
-            Command::PASS(pwd) => (self.listener)(Event::Misc(msg.prefix, String::from("PASS"), vec![pwd], tags)),
+            Command::PASS(pwd) => (self.listener)(Event::Misc(msg.prefix, String::from("PASS"), vec![pwd], tag_str)),
             Command::NICK(name) => {
-                let new_name = Self::short_name(msg.prefix);
-                self.change_nick(&name, &new_name);
-                (self.listener)(Event::Misc(None, String::from("NICK"), vec![new_name, name], tags))
-            },
-            Command::USER(user, mode, realname) => (self.listener)(Event::Misc(msg.prefix, String::from("USER"), vec![user, mode, realname], tags)),
-            Command::OPER(name, pwd) => (self.listener)(Event::Misc(msg.prefix, String::from("OPER"), vec![name, pwd], tags)),
-            Command::UserMODE(mode, nics) => (self.listener)(Event::Misc(msg.prefix, String::from("UserMODE"), vec![], tags)),
-            Command::SERVICE(service, nic, reserved, dist, tp, res_info,) => (self.listener)(Event::Misc(msg.prefix, String::from("SERVICE"), vec![service, nic, reserved, dist, tp, res_info], tags)),
+                let old_name = Self::short_name(msg.prefix);
+                self.change_nick(&old_name, &name);
+                (self.listener)(Event::Nick {
+                    old: old_name,
+                    new: name,
+                })
+            },
+            Command::USER(user, mode, realname) => (self.listener)(Event::Misc(msg.prefix, String::from("USER"), vec![user, mode, realname], tag_str)),
+            Command::OPER(name, pwd) => (self.listener)(Event::Misc(msg.prefix, String::from("OPER"), vec![name, pwd], tag_str)),
+            Command::UserMODE(target, modes) => (self.listener)(Event::Mode {
+                target,
+                by: Self::short_name(msg.prefix),
+                changes: modes.iter().map(|m| format!("{}", m)).collect(),
+            }),
+            Command::SERVICE(service, nic, reserved, dist, tp, res_info,) => (self.listener)(Event::Misc(msg.prefix, String::from("SERVICE"), vec![service, nic, reserved, dist, tp, res_info], tag_str)),
             Command::QUIT(comment) => {
                 let user_name = Self::short_name(msg.prefix);
-                self.remove_user(&user_name);
+                let split = self.split_quit(&user_name, comment.as_deref(), tags.batch());
+                if !split {
+                    self.remove_user(&user_name);
+                }
+                if self.nick.should_reclaim(&user_name) {
+                    self.reclaim_nick();
+                }
+                if split {
+                    return;
+                }
+                (self.listener)(Event::Quit {
+                    nick: user_name,
+                    reason: comment,
+                })
             },
-            Command::SQUIT(server, comment) => (self.listener)(Event::Misc(msg.prefix, String::from("SQUIT"), vec![server, comment], tags)),
-            Command::JOIN(list, keys, realname) => {
-                let user_name = Self::short_name(msg.prefix);
-                self.add_users(&list, &user_name);
+            Command::SQUIT(server, comment) => (self.listener)(Event::Squit {
+                server,
+                by: Self::short_name(msg.prefix),
+                reason: comment,
+            }),
+            Command::JOIN(list, account, realname) => {
+                let mask = Self::hostmask_of(&msg.prefix);
+                let user_name = mask.nick.clone();
+                // with extended-join "*" means the user isn't logged in
+                let (account, realname) = if self.has_cap("extended-join") {
+                    (account.filter(|a| a != "*"), realname)
+                } else {
+                    (None, None)
+                };
+                for channel in list.split(',') {
+                    // someone coming back from a netsplit, reported with the rest of the netjoin
+                    let rejoined = !self.is_me(&user_name)
+                        && self.netsplits.join(&user_name, channel, tags.batch(), now);
+                    if rejoined {
+                        self.channel_mut(channel).join_user(&mask);
+                    } else {
+                        self.user_joined(channel, &mask);
+                    }
+                    if self.has_cap("extended-join") {
+                        let ch = self.channel_mut(channel);
+                        ch.set_user_account(&user_name, account.as_deref());
+                        if let Some(ref realname) = realname {
+                            ch.set_user_real_name(&user_name, realname);
+                        }
+                    }
+                    if rejoined {
+                        continue;
+                    }
+                    (self.listener)(Event::Join {
+                        channel: String::from(channel),
+                        nick: user_name.clone(),
+                        account: account.clone(),
+                        real_name: realname.clone(),
+                    })
+                }
             },
             Command::PART(list, comment) => {
                 let user_name = Self::short_name(msg.prefix);
-                self.remove_user(&user_name);
-                (self.listener)(Event::Misc(Some(user_name), String::from("PART"), vec![list, comment.unwrap_or(String::new())], tags))
+                for channel in list.split(',') {
+                    self.user_left(channel, &user_name, Membership::Parted);
+                    (self.listener)(Event::Part {
+                        channel: String::from(channel),
+                        nick: user_name.clone(),
+                        reason: comment.clone(),
+                    })
+                }
             },
             Command::ChannelMODE(channel, modes) => {
-                self.change_user_chan_mode(channel, modes)
-            },
-            Command::TOPIC(channel, topic) => (self.listener)(Event::Misc(msg.prefix, String::from("TOPIC"), vec![channel, topic.unwrap_or(String::new())], tags)),
-            Command::NAMES(list, target) => (self.listener)(Event::Misc(msg.prefix, String::from("NAMES"), vec![list.unwrap_or(String::new()), target.unwrap_or(String::new())], tags)),
-            Command::LIST(list, target) => (self.listener)(Event::Misc(msg.prefix, String::from("LIST"), vec![list.unwrap_or(String::new()), target.unwrap_or(String::new())], tags)),
-            Command::INVITE(nickname, channel) => (self.listener)(Event::Misc(msg.prefix, String::from("INVITE"),vec![nickname, channel], tags)),
-            Command::KICK(list, user_list, comment) => (self.listener)(Event::Misc(msg.prefix, String::from("KICK"), vec![list, user_list, comment.unwrap_or(String::new())], tags)),
-            Command::PRIVMSG(target, text) => self.new_message(msg.prefix, target, text),
+                let by = Self::short_name(msg.prefix);
+                let modes = self.network.reparse_modes(&modes);
+                self.change_user_chan_mode(&channel, by, modes, time_stamp(&tags))
+            },
+            Command::TOPIC(channel, topic) => {
+                let by = Self::short_name(msg.prefix);
+                self.change_topic(&channel, &topic.unwrap_or_default(), Some(&by), Some(time_stamp(&tags)))
+            },
+            Command::NAMES(list, target) => (self.listener)(Event::Misc(msg.prefix, String::from("NAMES"), vec![list.unwrap_or(String::new()), target.unwrap_or(String::new())], tag_str)),
+            Command::LIST(list, target) => (self.listener)(Event::Misc(msg.prefix, String::from("LIST"), vec![list.unwrap_or(String::new()), target.unwrap_or(String::new())], tag_str)),
+            Command::INVITE(nickname, channel) => (self.listener)(Event::Invite {
+                channel,
+                nick: nickname,
+                by: Self::short_name(msg.prefix),
+            }),
+            Command::KICK(list, user_list, comment) => {
+                let by = Self::short_name(msg.prefix);
+                let channels: Vec<&str> = list.split(',').collect();
+                for (i, user_name) in user_list.split(',').enumerate() {
+                    let channel = channels.get(i).or(channels.first()).cloned().unwrap_or("");
+                    self.user_left(channel, user_name, Membership::Kicked);
+                    (self.listener)(Event::Kick {
+                        channel: String::from(channel),
+                        nick: String::from(user_name),
+                        by: by.clone(),
+                        reason: comment.clone(),
+                    })
+                }
+            },
+            Command::PRIVMSG(target, text) => self.new_message(msg.prefix, target, text, MessageKind::Message, &tags),
             Command::NOTICE(target, text) => {
                 if &target == "AUTH" {
-                    self.connection_status = ConnectionStatus::Authenticating;
-                } else if target.starts_with("#") || target.starts_with("&") {
-                    self.new_message(msg.prefix, target, text)
+                    self.set_connection_status(ConnectionStatus::Authenticating);
+                } else if self.network.is_channel(&target) {
+                    self.new_message(msg.prefix, target, text, MessageKind::Notice, &tags)
+                } else if let Some(ctcp) = Ctcp::parse(&text) {
+                    self.ctcp(Self::short_name(msg.prefix), target, ctcp, true, &tags)
                 } else {
-                    (self.listener)(Event::Misc(msg.prefix, String::from("NOTICE"), vec![target, text], tags))
-                }
-            },
-            Command::MOTD(target) => (self.listener)(Event::Misc(msg.prefix, String::from("MOTD"), vec![target.unwrap_or(String::new())], tags)),
-            Command::LUSERS(mask, target) => (self.listener)(Event::Misc(msg.prefix, String::from("LUSERS"), vec![mask.unwrap_or(String::new()), target.unwrap_or(String::new())], tags)),
-            Command::VERSION(version) => (self.listener)(Event::Misc(msg.prefix, String::from("VERSION"), vec![version.unwrap_or(String::new())], tags)),
-            Command::STATS(query, target) => (self.listener)(Event::Misc(msg.prefix, String::from("STATS"), vec![query.unwrap_or(String::new()), target.unwrap_or(String::new())], tags)),
-            Command::LINKS(server, mask) => (self.listener)(Event::Misc(msg.prefix, String::from("LINKS"), vec![server.unwrap_or(String::new()), mask.unwrap_or(String::new())], tags)),
-            Command::TIME(time) => (self.listener)(Event::Misc(msg.prefix, String::from("TIME"), vec![time.unwrap_or(String::new())], tags)),
-            Command::CONNECT(server, port, remote) => (self.listener)(Event::Misc(msg.prefix, String::from("CONNECT"), vec![server, port, remote.unwrap_or(String::new())], tags)),
-            Command::TRACE(target) => (self.listener)(Event::Misc(msg.prefix, String::from("TRACE"), vec![target.unwrap_or(String::new())], tags)),
-            Command::ADMIN(target) => (self.listener)(Event::Misc(msg.prefix, String::from("ADMIN"), vec![target.unwrap_or(String::new())], tags)),
-            Command::INFO(target) => (self.listener)(Event::Misc(msg.prefix, String::from("INFO"), vec![target.unwrap_or(String::new())], tags)),
-            Command::SERVLIST(mask, tp) => (self.listener)(Event::Misc(msg.prefix, String::from("SERVLIST"), vec![mask.unwrap_or(String::new()), tp.unwrap_or(String::new())], tags)),
-            Command::SQUERY(name, text) => (self.listener)(Event::Misc(msg.prefix, String::from("SQUERY"), vec![name, text], tags)),
-            Command::WHO(mask, operator) => (self.listener)(Event::Misc(msg.prefix, String::from("WHO"), vec![mask.unwrap_or(String::new()), format!("{:?}", operator)], tags)),
-            Command::WHOIS(target, list) => (self.listener)(Event::Misc(msg.prefix, String::from("WHOIS"), vec![target.unwrap_or(String::new()), list], tags)),
-            Command::WHOWAS(list, count, target) => (self.listener)(Event::Misc(msg.prefix, String::from("WHOWAS"), vec![list, count.unwrap_or(String::new()), target.unwrap_or(String::new())], tags)),
-            Command::KILL(name, comment) => (self.listener)(Event::Misc(msg.prefix, String::from("KILL"), vec![name, comment], tags)),
-            Command::PING(me, you) => (self.listener)(Event::Misc(msg.prefix, String::from("PING"), vec![], tags)),
-            Command::PONG(me, you) => (self.listener)(Event::Misc(msg.prefix, String::from("PONG"), vec![me, you.unwrap_or(String::new())], tags)),
-            Command::ERROR(message) => (self.listener)(Event::Misc(msg.prefix, String::from("ERROR"), vec![message], tags)),
-            Command::AWAY(message) => (self.listener)(Event::Misc(msg.prefix, String::from("AWAY"), vec![message.unwrap_or(String::new())], tags)),
-            Command::REHASH => (self.listener)(Event::Misc(msg.prefix, String::from("REHASH"), vec![], tags)),
-            Command::DIE => (self.listener)(Event::Misc(msg.prefix, String::from("DIE"), vec![], tags)),
-            Command::RESTART => (self.listener)(Event::Misc(msg.prefix, String::from("RESTART"), vec![], tags)),
-            Command::SUMMON(user, target, channel) => (self.listener)(Event::Misc(msg.prefix, String::from("SUMMON"), vec![user, target.unwrap_or(String::new()), channel.unwrap_or(String::new())], tags)),
-            Command::USERS(list) => (self.listener)(Event::Misc(msg.prefix, String::from("USERS"), vec![list.unwrap_or(String::new())], tags)),
-            Command::WALLOPS(text) => (self.listener)(Event::Misc(msg.prefix, String::from("WALLOPS"), vec![ text], tags)),
-            Command::USERHOST(list) => (self.listener)(Event::Misc(msg.prefix, String::from("USERHOST"), vec![ list.join(", ")], tags)),
-            Command::ISON(list) => (self.listener)(Event::Misc(msg.prefix, String::from("ISON"), vec![ list.join(" ")], tags)),
-            Command::SAJOIN(name, channel) => (self.listener)(Event::Misc(msg.prefix, String::from("SAJOIN"), vec![name, channel], tags)),
-            Command::SAMODE(target, modes, params) => (self.listener)(Event::Misc(msg.prefix, String::from("SAMODE"), vec![target, modes, params.unwrap_or(String::new())], tags)),
-            Command::SANICK(old, new) => (self.listener)(Event::Misc(msg.prefix, String::from("SANICK"), vec![old, new], tags)),
-            Command::SAPART(name, comment) => (self.listener)(Event::Misc(msg.prefix, String::from("SAPART"), vec![ name, comment], tags)),
-            Command::SAQUIT(name, comment) => (self.listener)(Event::Misc(msg.prefix, String::from("SAQUIT"), vec![name, comment], tags)),
-            Command::NICKSERV(message) => (self.listener)(Event::Misc(msg.prefix, String::from("NICKSERV"), vec![ message], tags)),
-            Command::CHANSERV(message) => (self.listener)(Event::Misc(msg.prefix, String::from("CHANSERV"), vec![ message], tags)),
-            Command::OPERSERV(message) => (self.listener)(Event::Misc(msg.prefix, String::from("OPERSERV"), vec![ message], tags)),
-            Command::BOTSERV(message) => (self.listener)(Event::Misc(msg.prefix, String::from("BOTSERV"), vec![ message], tags)),
-            Command::HOSTSERV(message) => (self.listener)(Event::Misc(msg.prefix, String::from("HOSTSERV"), vec![ message], tags)),
-            Command::MEMOSERV(message) => (self.listener)(Event::Misc(msg.prefix, String::from("MEMOSERV"), vec![ message], tags)),
-            Command::CAP(cmd, sub_cmd, arg, param) => (self.listener)(Event::Misc(msg.prefix, String::from("CAP"), vec![cmd.unwrap_or(String::new()), format!("{:?}", sub_cmd), arg.unwrap_or(String::new()), param.unwrap_or(String::new())], tags)),
-            Command::AUTHENTICATE(name) => (self.listener)(Event::Misc(msg.prefix, String::from("AUTHENTICATE"), vec![ name], tags)),
-            Command::ACCOUNT(name) => (self.listener)(Event::Misc(msg.prefix, String::from("ACCOUNT"), vec![ name], tags)),
-            Command::METADATA(target, sub_cmd, params, param) => (self.listener)(Event::Misc(msg.prefix, String::from("METADATA"), vec![target, format!("{:?}", sub_cmd), format!("{:?}", params), param.unwrap_or(String::new())], tags)),
-            Command::MONITOR(command, list) => (self.listener)(Event::Misc(msg.prefix, String::from("MONITOR"), vec![command, list.unwrap_or(String::new())], tags)),
-            Command::BATCH(operator, sub_cmd, params) => {
-                let params = if let Some(params) = params {
-                    params.join(", ")
-                } else {
-                    String::new()
-                };
-                (self.listener)(Event::Misc(msg.prefix, String::from("BATCH"), vec![operator, format!("{:?}", sub_cmd), params], tags));
+                    (self.listener)(Event::Notice {
+                        target,
+                        from: Self::short_name(msg.prefix),
+                        text,
+                    })
+                }
+            },
+            Command::MOTD(target) => (self.listener)(Event::Misc(msg.prefix, String::from("MOTD"), vec![target.unwrap_or(String::new())], tag_str)),
+            Command::LUSERS(mask, target) => (self.listener)(Event::Misc(msg.prefix, String::from("LUSERS"), vec![mask.unwrap_or(String::new()), target.unwrap_or(String::new())], tag_str)),
+            Command::VERSION(version) => (self.listener)(Event::Misc(msg.prefix, String::from("VERSION"), vec![version.unwrap_or(String::new())], tag_str)),
+            Command::STATS(query, target) => (self.listener)(Event::Misc(msg.prefix, String::from("STATS"), vec![query.unwrap_or(String::new()), target.unwrap_or(String::new())], tag_str)),
+            Command::LINKS(server, mask) => (self.listener)(Event::Misc(msg.prefix, String::from("LINKS"), vec![server.unwrap_or(String::new()), mask.unwrap_or(String::new())], tag_str)),
+            Command::TIME(time) => (self.listener)(Event::Misc(msg.prefix, String::from("TIME"), vec![time.unwrap_or(String::new())], tag_str)),
+            Command::CONNECT(server, port, remote) => (self.listener)(Event::Misc(msg.prefix, String::from("CONNECT"), vec![server, port, remote.unwrap_or(String::new())], tag_str)),
+            Command::TRACE(target) => (self.listener)(Event::Misc(msg.prefix, String::from("TRACE"), vec![target.unwrap_or(String::new())], tag_str)),
+            Command::ADMIN(target) => (self.listener)(Event::Misc(msg.prefix, String::from("ADMIN"), vec![target.unwrap_or(String::new())], tag_str)),
+            Command::INFO(target) => (self.listener)(Event::Misc(msg.prefix, String::from("INFO"), vec![target.unwrap_or(String::new())], tag_str)),
+            Command::SERVLIST(mask, tp) => (self.listener)(Event::Misc(msg.prefix, String::from("SERVLIST"), vec![mask.unwrap_or(String::new()), tp.unwrap_or(String::new())], tag_str)),
+            Command::SQUERY(name, text) => (self.listener)(Event::Misc(msg.prefix, String::from("SQUERY"), vec![name, text], tag_str)),
+            Command::WHO(mask, operator) => (self.listener)(Event::Misc(msg.prefix, String::from("WHO"), vec![mask.unwrap_or(String::new()), format!("{:?}", operator)], tag_str)),
+            Command::WHOIS(target, list) => (self.listener)(Event::Misc(msg.prefix, String::from("WHOIS"), vec![target.unwrap_or(String::new()), list], tag_str)),
+            Command::WHOWAS(list, count, target) => (self.listener)(Event::Misc(msg.prefix, String::from("WHOWAS"), vec![list, count.unwrap_or(String::new()), target.unwrap_or(String::new())], tag_str)),
+            Command::KILL(name, comment) => (self.listener)(Event::Kill {
+                nick: name,
+                by: Self::short_name(msg.prefix),
+                reason: comment,
+            }),
+            Command::PING(server, _) => {
+                (self.sender)(Command::PONG(server.clone(), None));
+                (self.listener)(Event::Ping {
+                    server,
+                })
+            },
+            // servers answer with either PONG <server> :<token> or PONG :<token>
+            Command::PONG(server, token) => {
+                if !self.keepalive.pong(token.as_ref().unwrap_or(&server), now) {
+                    return (self.listener)(Event::Pong {
+                        server,
+                        token,
+                    });
+                }
+                if let Some(lag) = self.keepalive.lag() {
+                    (self.listener)(Event::Lag(lag.as_millis() as u64))
+                }
+            },
+            Command::ERROR(message) => (self.listener)(Event::Error(message)),
+            Command::AWAY(message) => {
+                let nick = Self::short_name(msg.prefix);
+                for ch in self.channels.values_mut() {
+                    ch.set_user_away(&nick, message.is_some());
+                }
+                (self.listener)(Event::Away {
+                    nick,
+                    message,
+                })
+            },
+            Command::REHASH => (self.listener)(Event::Misc(msg.prefix, String::from("REHASH"), vec![], tag_str)),
+            Command::DIE => (self.listener)(Event::Misc(msg.prefix, String::from("DIE"), vec![], tag_str)),
+            Command::RESTART => (self.listener)(Event::Misc(msg.prefix, String::from("RESTART"), vec![], tag_str)),
+            Command::SUMMON(user, target, channel) => (self.listener)(Event::Misc(msg.prefix, String::from("SUMMON"), vec![user, target.unwrap_or(String::new()), channel.unwrap_or(String::new())], tag_str)),
+            Command::USERS(list) => (self.listener)(Event::Misc(msg.prefix, String::from("USERS"), vec![list.unwrap_or(String::new())], tag_str)),
+            Command::WALLOPS(text) => (self.listener)(Event::Wallops {
+                from: Self::short_name(msg.prefix),
+                text,
+            }),
+            Command::USERHOST(list) => (self.listener)(Event::Misc(msg.prefix, String::from("USERHOST"), vec![ list.join(", ")], tag_str)),
+            Command::ISON(list) => (self.listener)(Event::Misc(msg.prefix, String::from("ISON"), vec![ list.join(" ")], tag_str)),
+            Command::SAJOIN(name, channel) => (self.listener)(Event::Misc(msg.prefix, String::from("SAJOIN"), vec![name, channel], tag_str)),
+            Command::SAMODE(target, modes, params) => (self.listener)(Event::Misc(msg.prefix, String::from("SAMODE"), vec![target, modes, params.unwrap_or(String::new())], tag_str)),
+            Command::SANICK(old, new) => (self.listener)(Event::Misc(msg.prefix, String::from("SANICK"), vec![old, new], tag_str)),
+            Command::SAPART(name, comment) => (self.listener)(Event::Misc(msg.prefix, String::from("SAPART"), vec![ name, comment], tag_str)),
+            Command::SAQUIT(name, comment) => (self.listener)(Event::Misc(msg.prefix, String::from("SAQUIT"), vec![name, comment], tag_str)),
+            Command::NICKSERV(message) => (self.listener)(Event::Misc(msg.prefix, String::from("NICKSERV"), vec![ message], tag_str)),
+            Command::CHANSERV(message) => (self.listener)(Event::Misc(msg.prefix, String::from("CHANSERV"), vec![ message], tag_str)),
+            Command::OPERSERV(message) => (self.listener)(Event::Misc(msg.prefix, String::from("OPERSERV"), vec![ message], tag_str)),
+            Command::BOTSERV(message) => (self.listener)(Event::Misc(msg.prefix, String::from("BOTSERV"), vec![ message], tag_str)),
+            Command::HOSTSERV(message) => (self.listener)(Event::Misc(msg.prefix, String::from("HOSTSERV"), vec![ message], tag_str)),
+            Command::MEMOSERV(message) => (self.listener)(Event::Misc(msg.prefix, String::from("MEMOSERV"), vec![ message], tag_str)),
+            Command::CAP(target, sub_cmd, arg, param) => {
+                self.cap(&sub_cmd, arg.as_deref(), param.as_deref());
+                let args = vec![target, arg, param].into_iter().flatten().collect();
+                (self.listener)(Event::Cap {
+                    sub_command: String::from(sub_cmd.to_str()),
+                    args,
+                })
+            },
+            Command::AUTHENTICATE(name) => {
+                let replies = self.sasl.as_mut().map(|s| s.respond(&name)).unwrap_or_default();
+                for reply in replies {
+                    (self.sender)(reply);
+                }
+                (self.listener)(Event::Authenticate(name))
+            },
+            Command::ACCOUNT(name) => {
+                let nick = Self::short_name(msg.prefix);
+                let account = if &name == "*" { None } else { Some(name) };
+                for ch in self.channels.values_mut() {
+                    ch.set_user_account(&nick, account.as_deref());
+                }
+                (self.listener)(Event::Account {
+                    nick,
+                    account,
+                })
+            },
+            Command::METADATA(target, sub_cmd, params, param) => (self.listener)(Event::Metadata {
+                target,
+                sub_command: sub_cmd.map(|c| String::from(c.to_str())),
+                params: params.unwrap_or(vec![]),
+                value: param,
+            }),
+            Command::MONITOR(command, list) => (self.listener)(Event::Monitor {
+                command,
+                targets: list.map(|l| l.split(',').map(String::from).collect()).unwrap_or(vec![]),
+            }),
+            Command::BATCH(reference, sub_cmd, params) => {
+                if let Some(id) = reference.strip_prefix('+') {
+                    let kind = sub_cmd.map(|c| String::from(c.to_str())).unwrap_or_default();
+                    if let Some(dropped) = self.batches.start(id, &kind, params.unwrap_or_default(), tags, now) {
+                        self.deliver(dropped);
+                    }
+                } else if let Some(id) = reference.strip_prefix('-') {
+                    if let Some(batch) = self.batches.end(id) {
+                        self.deliver(batch);
+                    }
+                }
+            },
+            Command::CHGHOST(user, host) => {
+                let nick = Self::short_name(msg.prefix);
+                self.update_host(&Hostmask::new(&nick, Some(&user), Some(&host)));
+                (self.listener)(Event::ChgHost {
+                    nick,
+                    user,
+                    host,
+                })
             },
-            Command::CHGHOST(user, host) => (self.listener)(Event::Misc(msg.prefix, String::from("CHGHOST"), vec![user, host], tags)),
             Command::Response(res, args, suffix) => self.response(res, args, suffix) ,
-            Command::Raw(command, params, param) => (self.listener)(Event::Misc(msg.prefix, String::from("Raw"), vec![command, params.join(", "), param.unwrap_or(String::new())], tags)),
+            // channel MODEs from handle_line, and any MODE whose last argument
+            // was sent as trailing since the irc crate gives up on those
+            Command::Raw(ref command, ref params, ref param) if command == "MODE" && !params.is_empty() => {
+                let by = Self::short_name(msg.prefix);
+                let mut args: Vec<String> = params.iter().skip(2).cloned().collect();
+                args.extend(param.clone());
+                let modes = params.get(1).cloned().unwrap_or_default();
+                if self.network.is_channel(&params[0]) {
+                    let modes = self.network.parse_modes(&modes, &args);
+                    self.change_user_chan_mode(&params[0], by, modes, time_stamp(&tags))
+                } else {
+                    (self.listener)(Event::Mode {
+                        target: params[0].clone(),
+                        by,
+                        changes: vec![modes],
+                    })
+                }
+            },
+            // RPL_WHOSPCRPL, the WHOX reply
+            Command::Raw(ref command, ref params, ref param) if command == "354" => {
+                match WhoReply::parse_whox(who::WHOX_FIELDS, who::WHOX_TOKEN, params, param.as_deref()) {
+                    Some(reply) => self.who_reply(reply),
+                    // someone else's WHOX
+                    None => (self.listener)(Event::Misc(msg.prefix, String::from("Raw"), vec![command.clone(), params.join(", "), param.clone().unwrap_or_default()], tag_str)),
+                }
+            },
+            // RPL_QUIETLIST and RPL_ENDOFQUIETLIST put the mode letter after the channel
+            Command::Raw(ref command, ref params, _) if command == "728" && params.len() > 3 => {
+                let mode = params[2].chars().next().unwrap_or(modes::QUIET);
+                let args: Vec<String> = params.iter().take(2).chain(params.iter().skip(3)).cloned().collect();
+                self.list_entry(mode, &args)
+            },
+            Command::Raw(ref command, ref params, _) if command == "729" && params.len() > 2 => {
+                let mode = params[2].chars().next().unwrap_or(modes::QUIET);
+                self.end_list(mode, params)
+            },
+            Command::Raw(command, params, param) => {
+                if let Some(code) = whois::whois_code(&command) {
+                    return self.whois_line(code, params, param);
+                }
+                match ServerErrorKind::from_code(&command) {
+                    Some(kind) => self.server_error(kind, params, param),
+                    None => (self.listener)(Event::Misc(msg.prefix, String::from("Raw"), vec![command, params.join(", "), param.unwrap_or(String::new())], tag_str)),
+                }
+            },
             
         }
     }
 
-    fn new_message(&mut self, prefix: Option<String>, channel: String, text: String) {
+    fn new_message(&mut self, prefix: Option<String>, target: String, text: String, kind: MessageKind, tags: &MessageTags) {
         let user_name = match prefix {
-            Some(p) => {
-                let parts: Vec<&str> = p.split('!').collect();
-                String::from(parts[0])
-            },
+            Some(p) => String::from(Source::parse(&p).name()),
             None => String::from("Unknown")
         };
-        match self.channels.get_mut(&channel) {
-            Some(ch) => {
-                let time_stamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::new(0, 0)).as_secs();
-                let new_message = ChannelMessage {
-                    time_stamp: format!("{}", time_stamp),
-                    user_name: user_name,
-                    content: text,
-                };
+        let (content, kind) = match Ctcp::parse(&text) {
+            Some(ref ctcp) if ctcp.is_action() => (ctcp.params.clone().unwrap_or_default(), MessageKind::Action),
+            Some(ctcp) => return self.ctcp(user_name, target, ctcp, kind == MessageKind::Notice, tags),
+            None => (text, kind),
+        };
+        let new_message = ChannelMessage::new(user_name, content, kind).with_tags(tags);
+        let channel = String::from(self.network.status_target(&target));
+        if self.network.is_channel(&channel) {
+            let key = self.key(&channel);
+            // a channel we aren't in, e.g. our own echo to a channel without +n,
+            // is passed on but not kept
+            if let Some(ch) = self.channels.get_mut(&key) {
                 ch.add_message(new_message.clone());
-                (self.listener)(Event::NewMessage(channel, new_message))
+            }
+            (self.listener)(Event::NewMessage(channel, new_message))
+        } else {
+            // a message we sent shows up here with echo-message, file it
+            // under whoever we sent it to
+            let other = if self.is_me(&new_message.user_name) {
+                target
+            } else {
+                new_message.user_name.clone()
+            };
+            let key = self.key(&other);
+            let query = self.queries.entry(key).or_insert_with(|| Query::with_nick(&other));
+            query.rename(&other);
+            query.add_message(new_message.clone());
+            (self.listener)(Event::DirectMessage(other, new_message))
+        }
+    }
+
+    fn cap(&mut self, sub_cmd: &CapSubCommand, arg: Option<&str>, param: Option<&str>) {
+        // a lone "*" before the list means more lines of the same reply follow
+        let (caps, more) = match (arg, param) {
+            (Some("*"), Some(caps)) => (caps, true),
+            (_, Some(caps)) => (caps, false),
+            (Some(caps), None) => (caps, false),
+            (None, None) => ("", false),
+        };
+        let before = self.caps.enabled();
+        for cmd in self.caps.handle(sub_cmd, caps, more) {
+            (self.sender)(cmd);
+        }
+        let enabled = self.caps.enabled();
+        if enabled != before {
+            (self.listener)(Event::Capabilities(enabled));
+        }
+        if self.caps.is_held() {
+            self.start_sasl();
+        }
+    }
+
+    fn start_sasl(&mut self) {
+        let mechanisms = self.caps.value("sasl").map(String::from);
+        let command = match self.sasl {
+            Some(ref mut sasl) if sasl.state() == SaslState::Idle && sasl.supported_by(mechanisms.as_deref()) => {
+                sasl.start(Instant::now())
+            },
+            Some(ref sasl) if sasl.in_progress() => return,
+            _ => {
+                // nothing we can log in with, carry on registering without it
+                if let Some(ref mut sasl) = self.sasl {
+                    sasl.failed();
+                }
+                return self.sasl_finished();
             },
-            _ => println!("Unable to get channel {}", &channel)
+        };
+        self.set_connection_status(ConnectionStatus::Authenticating);
+        (self.sender)(command);
+        self.sasl_event();
+    }
+
+    // success or failure, registration can go ahead now
+    fn sasl_finished(&mut self) {
+        for cmd in self.caps.release() {
+            (self.sender)(cmd);
         }
+        self.sasl_event();
+    }
+
+    fn sasl_event(&mut self) {
+        if let Some(ref sasl) = self.sasl {
+            (self.listener)(Event::Sasl {
+                state: sasl.state(),
+                account: sasl.account().map(String::from),
+            })
+        }
+    }
 
+    fn ctcp(&mut self, from: String, target: String, ctcp: Ctcp, reply: bool, tags: &MessageTags) {
+        // a CTCP in a batch isn't asking us anything right now, even when
+        // the batch was given up on and the rest of it arrives one line at a
+        // time. One from ourselves would have us answering our own echo
+        let batched = self.replaying || tags.batch().is_some();
+        if !reply && !batched && !self.is_me(&from) {
+            if let Some(ref mut responder) = self.ctcp_responder {
+                if let Some(answer) = responder.reply(&from, &ctcp, Instant::now()) {
+                    (self.sender)(Command::NOTICE(from.clone(), answer.to_string()));
+                }
+            }
+        }
+        (self.listener)(Event::Ctcp {
+            from,
+            target,
+            command: ctcp.command,
+            params: ctcp.params,
+            reply,
+        })
     }
 
     fn response(&mut self, res: Response, args: Vec<String>, suffix: Option<String>) {
         match res {
             Response::RPL_WELCOME => {
-                let msg = suffix.unwrap_or(String::new());
+                if let Some(nick) = args.first() {
+                    self.nick.set_current(nick);
+                }
+                let msg = suffix.unwrap_or_default();
                 self.add_welcome(&msg);
-                self.connection_status = ConnectionStatus::Connected;
-                (self.listener)(Event::Welcome(msg))
+                self.caps.registered();
+                self.set_connection_status(ConnectionStatus::Connected);
+                (self.listener)(Event::Welcome(msg));
+                self.rejoin();
             }
+            Response::RPL_ISUPPORT => {
+                // <nick> <token>... :are supported by this server
+                let tokens: Vec<String> = args.into_iter().skip(1).collect();
+                self.network.apply(&tokens);
+                let case_mapping = CaseMapping::from(self.network.case_mapping());
+                self.set_case_mapping(case_mapping);
+                self.nick.set_nick_len(self.network.nick_len());
+                (self.listener)(Event::Isupport(tokens))
+            },
+            // also sent when we message someone who is away
+            Response::RPL_AWAY if args.get(1).map(|n| self.whois.is_pending(&self.key(n))).unwrap_or(false) => {
+                self.whois_line(res as u16, args, suffix)
+            },
             Response::RPL_AWAY => (self.listener)(Event::Misc(None, String::from("RPL_AWAY"), args, suffix)),
             Response::RPL_UNAWAY => (self.listener)(Event::Misc(None, String::from("RPL_UNAWAY"), args, suffix)),
             Response::RPL_NOWAWAY => (self.listener)(Event::Misc(None, String::from("RPL_UNAWAY"), args, suffix)),
-            Response::RPL_WHOISUSER => (self.listener)(Event::Misc(None, String::from("RPL_WHOISUSER"), args, suffix)),
-            Response::RPL_WHOISSERVER => (self.listener)(Event::Misc(None, String::from("RPL_WHOISSERVER"), args, suffix)),
-            Response::RPL_WHOISOPERATOR => (self.listener)(Event::Misc(None, String::from("RPL_WHOISOPERATOR"), args, suffix)),
-            Response::RPL_WHOISIDLE => (self.listener)(Event::Misc(None, String::from("RPL_WHOISIDLE"), args, suffix)),
-            Response::RPL_ENDOFWHOIS => (self.listener)(Event::Misc(None, String::from("RPL_ENDOFWHOIS"), args, suffix)),
-            Response::RPL_WHOISCHANNELS => (self.listener)(Event::Misc(None, String::from("RPL_WHOISCHANNELS"), args, suffix)),
+            Response::RPL_WHOISUSER
+            | Response::RPL_WHOISSERVER
+            | Response::RPL_WHOISOPERATOR
+            | Response::RPL_WHOISIDLE
+            | Response::RPL_WHOISCHANNELS
+            | Response::RPL_WHOISCERTFP => self.whois_line(res as u16, args, suffix),
+            Response::RPL_ENDOFWHOIS => self.end_whois(&args),
             Response::RPL_WHOWASUSER => (self.listener)(Event::Misc(None, String::from("RPL_WHOWASUSER"), args, suffix)),
             Response::RPL_ENDOFWHOWAS => (self.listener)(Event::Misc(None, String::from("RPL_ENDOFWHOWAS"), args, suffix)),
-            Response::RPL_LISTSTART => (self.listener)(Event::Misc(None, String::from("RPL_LISTSTART"), args, suffix)),
-            Response::RPL_LIST => (self.listener)(Event::Misc(None, String::from("RPL_LIST"), args, suffix)),
-            Response::RPL_LISTEND => (self.listener)(Event::Misc(None, String::from("RPL_LISTEND"), args, suffix)),
+            Response::RPL_LISTSTART => self.directory.start(),
+            // <nick> <channel> <users> :<topic>
+            Response::RPL_LIST => {
+                if args.len() > 2 {
+                    let users = args[2].parse().unwrap_or(0);
+                    self.directory.entry(&args[1], users, &suffix.unwrap_or_default());
+                }
+            },
+            Response::RPL_LISTEND => {
+                let channels = self.directory.end(self.case_mapping, now());
+                (self.listener)(Event::ChannelDirectory {
+                    channels,
+                })
+            },
             Response::RPL_UNIQOPIS => (self.listener)(Event::Misc(None, String::from("RPL_UNIQOPIS"), args, suffix)),
-            Response::RPL_CHANNELMODEIS => (self.listener)(Event::Misc(None, String::from("RPL_CHANNELMODEIS"), args, suffix)),
+            Response::RPL_CHANNELMODEIS => {
+                // <nick> <channel> <modes> <args>...
+                let mut args: Vec<String> = args.into_iter().skip(1).chain(suffix).collect();
+                if args.len() < 2 {
+                    return;
+                }
+                let rest = args.split_off(2);
+                let channel = args[0].clone();
+                let modes = self.network.parse_modes(&args[1], &rest);
+                if let Some(ch) = self.channels.get_mut(&self.case_mapping.key(&channel)) {
+                    ch.clear_mode_settings();
+                }
+                self.apply_chan_modes(&channel, modes, "", time_stamp(&MessageTags::default()));
+                if let Some(modes) = self.channel_modes(&channel) {
+                    (self.listener)(Event::ChannelModes {
+                        channel,
+                        modes,
+                    })
+                }
+            },
             Response::RPL_TOPIC => {
                 let channel = match args.iter().last() {
                     Some(ch) => ch,
                     _ => "",
                 };
-                self.add_ch_topic(&channel, &suffix.unwrap_or(String::new()));
+                self.add_ch_topic(channel, &suffix.unwrap_or_default());
+            },
+            Response::RPL_NOTOPIC => {
+                if let Some(channel) = args.get(1) {
+                    self.add_ch_topic(channel, "");
+                }
+            },
+            Response::RPL_TOPICWHOTIME => {
+                // <nick> <channel> <setter> <time>, some servers send the time as the suffix
+                let mut args = args.into_iter().skip(1).chain(suffix);
+                if let (Some(channel), Some(setter)) = (args.next(), args.next()) {
+                    let at = args.next().and_then(|t| t.parse().ok());
+                    let setter = Hostmask::parse(&setter);
+                    let key = self.key(&channel);
+                    if let Some(ch) = self.channels.get_mut(&key) {
+                        ch.set_topic_setter(Some(setter.nick()), at);
+                    }
+                }
             },
-            Response::RPL_TOPICWHOTIME => (),
             Response::RPL_INVITING => (self.listener)(Event::Misc(None, String::from("RPL_INVITING"), args, suffix)),
             Response::RPL_SUMMONING => (self.listener)(Event::Misc(None, String::from("RPL_SUMMONING"), args, suffix)),
-            Response::RPL_INVITELIST => (self.listener)(Event::Misc(None, String::from("RPL_INVITELIST"), args, suffix)),
-            Response::RPL_ENDOFINVITELIST => (self.listener)(Event::Misc(None, String::from("RPL_ENDOFINVITELIST"), args, suffix)),
-            Response::RPL_EXCEPTLIST => (self.listener)(Event::Misc(None, String::from("RPL_EXCEPTLIST"), args, suffix)),
-            Response::RPL_ENDOFEXCEPTLIST => (self.listener)(Event::Misc(None, String::from("RPL_ENDOFEXCEPTLIST"), args, suffix)),
+            Response::RPL_INVITELIST => self.list_entry(modes::INVITE_EXCEPTION, &args),
+            Response::RPL_ENDOFINVITELIST => self.end_list(modes::INVITE_EXCEPTION, &args),
+            Response::RPL_EXCEPTLIST => self.list_entry(modes::EXCEPTION, &args),
+            Response::RPL_ENDOFEXCEPTLIST => self.end_list(modes::EXCEPTION, &args),
             Response::RPL_VERSION => (self.listener)(Event::Misc(None, String::from("RPL_VERSION"), args, suffix)),
-            Response::RPL_WHOREPLY => (self.listener)(Event::Misc(None, String::from("RPL_WHOREPLY"), args, suffix)),
-            Response::RPL_ENDOFWHO => (self.listener)(Event::Misc(None, String::from("RPL_ENDOFWHO"), args, suffix)),
+            Response::RPL_WHOREPLY => {
+                if let Some(reply) = WhoReply::parse(&args, suffix.as_deref()) {
+                    self.who_reply(reply);
+                }
+            },
+            // <nick> <mask>
+            Response::RPL_ENDOFWHO => {
+                let users = self.who_replies.drain(..).collect();
+                (self.listener)(Event::Who {
+                    mask: args.into_iter().nth(1).unwrap_or_default(),
+                    users,
+                })
+            },
             Response::RPL_NAMREPLY => {
                 let channel = args.into_iter().last().expect("can't get last arg");
                 let names = suffix.expect("names suffix is None");
                 self.add_users(&channel, &names);
             },
             Response::RPL_ENDOFNAMES => {
-                match args.iter().last() {
-                    Some(name) => {
-                        match self.channels.get(name) {
-                            Some(ch) => (self.listener)(Event::NewUsers(name.to_string(), ch.users())),
-                            None => ()
-                        }
-                    },
-                    None => ()
+                if let Some(name) = args.iter().last() {
+                    let key = self.key(name);
+                    let users = match self.channels.get(&key) {
+                        Some(ch) => Some((String::from(ch.name()), ch.users())),
+                        None => self.outside_names.remove(&key).map(|nicks| (name.clone(), nicks)),
+                    };
+                    if let Some((channel, users)) = users {
+                        (self.listener)(Event::NewUsers(channel, users))
+                    }
                 }
             },
             Response::RPL_LINKS => (self.listener)(Event::Misc(None, String::from("RPL_LINKS"), args, suffix)),
             Response::RPL_ENDOFLINKS => (self.listener)(Event::Misc(None, String::from("RPL_ENDOFLINKS"), args, suffix)),
-            Response::RPL_BANLIST => (self.listener)(Event::Misc(None, String::from("RPL_BANLIST"), args, suffix)),
-            Response::RPL_ENDOFBANLIST => (self.listener)(Event::Misc(None, String::from("RPL_ENDOFBANLIST"), args, suffix)),
+            Response::RPL_BANLIST => self.list_entry(modes::BAN, &args),
+            Response::RPL_ENDOFBANLIST => self.end_list(modes::BAN, &args),
             Response::RPL_INFO => (self.listener)(Event::Misc(None, String::from("RPL_INFO"), args, suffix)),
             Response::RPL_ENDOFINFO => (self.listener)(Event::Misc(None, String::from("RPL_ENDOFINFO"), args, suffix)),
             Response::RPL_MOTD => {
-                match suffix {
-                    Some(text) => self.add_motd(text),
-                    _ => ()
+                if let Some(text) = suffix {
+                    self.add_motd(text)
                 }
             },
             Response::RPL_ENDOFMOTD => (self.listener)(Event::Motd(self.get_motd())),
@@ -388,7 +1672,6 @@ impl Server {
             Response::RPL_TRYAGAIN => (self.listener)(Event::Misc(None, String::from("RPL_TRYAGAIN"), args, suffix)),
             Response::RPL_LOCALUSERS => (self.listener)(Event::Misc(None, String::from("RPL_LOCALUSERS"), args, suffix)),
             Response::RPL_GLOBALUSERS => (self.listener)(Event::Misc(None, String::from("RPL_GLOBALUSERS"), args, suffix)),
-            Response::RPL_WHOISCERTFP => (self.listener)(Event::Misc(None, String::from("RPL_WHOISCERTFP"), args, suffix)),
             Response::RPL_MONONLINE => (self.listener)(Event::Misc(None, String::from("RPL_MONONLINE"), args, suffix)),
             Response::RPL_MONOFFLINE => (self.listener)(Event::Misc(None, String::from("RPL_MONOFFLINE"), args, suffix)),
             Response::RPL_MONLIST => (self.listener)(Event::Misc(None, String::from("RPL_MONLIST"), args, suffix)),
@@ -396,78 +1679,365 @@ impl Server {
             Response::RPL_WHOISKEYVALUE => (self.listener)(Event::Misc(None, String::from("RPL_WHOISKEYVALUE"), args, suffix)),
             Response::RPL_KEYVALUE => (self.listener)(Event::Misc(None, String::from("RPL_KEYVALUE"), args, suffix)),
             Response::RPL_METADATAEND => (self.listener)(Event::Misc(None, String::from("RPL_METADATAEND"), args, suffix)),
-            Response::RPL_LOGGEDIN => (self.listener)(Event::Misc(None, String::from("RPL_LOGGEDIN"), args, suffix)),
-            Response::RPL_LOGGEDOUT => (self.listener)(Event::Misc(None, String::from("RPL_LOGGEDOUT"), args, suffix)),
-            Response::RPL_SASLSUCCESS => (self.listener)(Event::Misc(None, String::from("RPL_SASLSUCCESS"), args, suffix)),
+            Response::RPL_LOGGEDIN => {
+                // <nick> <nick!user@host> <account> :You are now logged in as <account>
+                let account = args.get(2).cloned();
+                if let (Some(sasl), Some(account)) = (self.sasl.as_mut(), account.as_ref()) {
+                    sasl.logged_in(account);
+                }
+                (self.listener)(Event::Account {
+                    nick: args.first().cloned().unwrap_or_default(),
+                    account,
+                })
+            },
+            Response::RPL_LOGGEDOUT => {
+                if let Some(ref mut sasl) = self.sasl {
+                    sasl.logged_out();
+                }
+                (self.listener)(Event::Account {
+                    nick: args.first().cloned().unwrap_or_default(),
+                    account: None,
+                })
+            },
+            Response::RPL_SASLSUCCESS => {
+                if let Some(ref mut sasl) = self.sasl {
+                    sasl.succeeded();
+                }
+                self.sasl_finished();
+            },
             Response::RPL_SASLMECHS => (self.listener)(Event::Misc(None, String::from("RPL_SASLMECHS"), args, suffix)),
-            Response::ERR_UNKNOWNERROR => (self.listener)(Event::Misc(None, String::from("ERR_UNKNOWNERROR"), args, suffix)),
-            Response::ERR_NOSUCHNICK => (self.listener)(Event::Misc(None, String::from("ERR_NOSUCHNICK"), args, suffix)),
-            Response::ERR_NOSUCHSERVER => (self.listener)(Event::Misc(None, String::from("ERR_NOSUCHSERVER"), args, suffix)),
-            Response::ERR_NOSUCHCHANNEL => (self.listener)(Event::Misc(None, String::from("ERR_NOSUCHCHANNEL"), args, suffix)),
-            Response::ERR_CANNOTSENDTOCHAN => (self.listener)(Event::Misc(None, String::from("ERR_CANNOTSENDTOCHAN"), args, suffix)),
-            Response::ERR_TOOMANYCHANNELS => (self.listener)(Event::Misc(None, String::from("ERR_TOOMANYCHANNELS"), args, suffix)),
-            Response::ERR_WASNOSUCHNICK => (self.listener)(Event::Misc(None, String::from("ERR_WASNOSUCHNICK"), args, suffix)),
-            Response::ERR_TOOMANYTARGETS => (self.listener)(Event::Misc(None, String::from("ERR_TOOMANYTARGETS"), args, suffix)),
-            Response::ERR_NOSUCHSERVICE => (self.listener)(Event::Misc(None, String::from("ERR_NOSUCHSERVICE"), args, suffix)),
-            Response::ERR_NOORIGIN => (self.listener)(Event::Misc(None, String::from("ERR_NOORIGIN"), args, suffix)),
-            Response::ERR_NORECIPIENT => (self.listener)(Event::Misc(None, String::from("ERR_NORECIPIENT"), args, suffix)),
-            Response::ERR_NOTEXTTOSEND => (self.listener)(Event::Misc(None, String::from("ERR_NOTEXTTOSEND"), args, suffix)),
-            Response::ERR_NOTOPLEVEL => (self.listener)(Event::Misc(None, String::from("ERR_NOTOPLEVEL"), args, suffix)),
-            Response::ERR_WILDTOPLEVEL => (self.listener)(Event::Misc(None, String::from("ERR_WILDTOPLEVEL"), args, suffix)),
-            Response::ERR_BADMASK => (self.listener)(Event::Misc(None, String::from("ERR_BADMASK"), args, suffix)),
-            Response::ERR_UNKNOWNCOMMAND => (self.listener)(Event::Misc(None, String::from("ERR_UNKNOWNCOMMAND"), args, suffix)),
-            Response::ERR_NOMOTD => (self.listener)(Event::Misc(None, String::from("ERR_NOMOTD"), args, suffix)),
-            Response::ERR_NOADMININFO => (self.listener)(Event::Misc(None, String::from("ERR_NOADMININFO"), args, suffix)),
-            Response::ERR_FILEERROR => (self.listener)(Event::Misc(None, String::from("ERR_FILEERROR"), args, suffix)),
-            Response::ERR_NONICKNAMEGIVEN => (self.listener)(Event::Misc(None, String::from("ERR_NONICKNAMEGIVEN"), args, suffix)),
-            Response::ERR_ERRONEOUSNICKNAME => (self.listener)(Event::Misc(None, String::from("ERR_ERRONEOUSNICKNAME"), args, suffix)),
-            Response::ERR_NICKNAMEINUSE => (self.listener)(Event::Misc(None, String::from("ERR_NICKNAMEINUSE"), args, suffix)),
-            Response::ERR_NICKCOLLISION => (self.listener)(Event::Misc(None, String::from("ERR_NICKCOLLISION"), args, suffix)),
-            Response::ERR_UNAVAILRESOURCE => (self.listener)(Event::Misc(None, String::from("ERR_UNAVAILRESOURCE"), args, suffix)),
-            Response::ERR_USERNOTINCHANNEL => (self.listener)(Event::Misc(None, String::from("ERR_USERNOTINCHANNEL"), args, suffix)),
-            Response::ERR_NOTONCHANNEL => (self.listener)(Event::Misc(None, String::from("ERR_NOTONCHANNEL"), args, suffix)),
-            Response::ERR_USERONCHANNEL => (self.listener)(Event::Misc(None, String::from("ERR_USERONCHANNEL"), args, suffix)),
-            Response::ERR_NOLOGIN => (self.listener)(Event::Misc(None, String::from("ERR_NOLOGIN"), args, suffix)),
-            Response::ERR_SUMMONDISABLED => (self.listener)(Event::Misc(None, String::from("ERR_SUMMONDISABLED"), args, suffix)),
-            Response::ERR_USERSDISABLED => (self.listener)(Event::Misc(None, String::from("ERR_USERSDISABLED"), args, suffix)),
-            Response::ERR_NOTREGISTERED => (self.listener)(Event::Misc(None, String::from("ERR_NOTREGISTERED"), args, suffix)),
-            Response::ERR_NEEDMOREPARAMS => (self.listener)(Event::Misc(None, String::from("ERR_NEEDMOREPARAMS"), args, suffix)),
-            Response::ERR_ALREADYREGISTRED => (self.listener)(Event::Misc(None, String::from("ERR_ALREADYREGISTRED"), args, suffix)),
-            Response::ERR_NOPERMFORHOST => (self.listener)(Event::Misc(None, String::from("ERR_NOPERMFORHOST"), args, suffix)),
-            Response::ERR_PASSWDMISMATCH => (self.listener)(Event::Misc(None, String::from("ERR_PASSWDMISMATCH"), args, suffix)),
-            Response::ERR_YOUREBANNEDCREEP => (self.listener)(Event::Misc(None, String::from("ERR_YOUREBANNEDCREEP"), args, suffix)),
-            Response::ERR_YOUWILLBEBANNED => (self.listener)(Event::Misc(None, String::from("ERR_YOUWILLBEBANNED"), args, suffix)),
-            Response::ERR_KEYSET => (self.listener)(Event::Misc(None, String::from("ERR_KEYSET"), args, suffix)),
-            Response::ERR_CHANNELISFULL => (self.listener)(Event::Misc(None, String::from("ERR_CHANNELISFULL"), args, suffix)),
-            Response::ERR_UNKNOWNMODE => (self.listener)(Event::Misc(None, String::from("ERR_UNKNOWNMODE"), args, suffix)),
-            Response::ERR_INVITEONLYCHAN => (self.listener)(Event::Misc(None, String::from("ERR_INVITEONLYCHAN"), args, suffix)),
-            Response::ERR_BANNEDFROMCHAN => (self.listener)(Event::Misc(None, String::from("ERR_BANNEDFROMCHAN"), args, suffix)),
-            Response::ERR_BADCHANNELKEY => (self.listener)(Event::Misc(None, String::from("ERR_BADCHANNELKEY"), args, suffix)),
-            Response::ERR_BADCHANMASK => (self.listener)(Event::Misc(None, String::from("ERR_BADCHANMASK"), args, suffix)),
-            Response::ERR_NOCHANMODES => (self.listener)(Event::Misc(None, String::from("ERR_NOCHANMODES"), args, suffix)),
-            Response::ERR_BANLISTFULL => (self.listener)(Event::Misc(None, String::from("ERR_BANLISTFULL"), args, suffix)),
-            Response::ERR_NOPRIVILEGES => (self.listener)(Event::Misc(None, String::from("ERR_NOPRIVILEGES"), args, suffix)),
-            Response::ERR_CHANOPRIVSNEEDED => (self.listener)(Event::Misc(None, String::from("ERR_CHANOPRIVSNEEDED"), args, suffix)),
-            Response::ERR_CANTKILLSERVER => (self.listener)(Event::Misc(None, String::from("ERR_CANTKILLSERVER"), args, suffix)),
-            Response::ERR_RESTRICTED => (self.listener)(Event::Misc(None, String::from("ERR_RESTRICTED"), args, suffix)),
-            Response::ERR_UNIQOPPRIVSNEEDED => (self.listener)(Event::Misc(None, String::from("ERR_UNIQOPPRIVSNEEDED"), args, suffix)),
-            Response::ERR_NOOPERHOST => (self.listener)(Event::Misc(None, String::from("ERR_NOOPERHOST"), args, suffix)),
-            Response::ERR_UMODEUNKNOWNFLAG => (self.listener)(Event::Misc(None, String::from("ERR_UMODEUNKNOWNFLAG"), args, suffix)),
-            Response::ERR_USERSDONTMATCH => (self.listener)(Event::Misc(None, String::from("ERR_USERSDONTMATCH"), args, suffix)),
-            Response::ERR_NOPRIVS => (self.listener)(Event::Misc(None, String::from("ERR_NOPRIVS"), args, suffix)),
-            Response::ERR_MONLISTFULL => (self.listener)(Event::Misc(None, String::from("ERR_MONLISTFULL"), args, suffix)),
-            Response::ERR_METADATALIMIT => (self.listener)(Event::Misc(None, String::from("ERR_METADATALIMIT"), args, suffix)),
-            Response::ERR_TARGETINVALID => (self.listener)(Event::Misc(None, String::from("ERR_TARGETINVALID"), args, suffix)),
-            Response::ERR_NOMATCHINGKEY => (self.listener)(Event::Misc(None, String::from("ERR_NOMATCHINGKEY"), args, suffix)),
-            Response::ERR_KEYINVALID => (self.listener)(Event::Misc(None, String::from("ERR_KEYINVALID"), args, suffix)),
-            Response::ERR_KEYNOTSET => (self.listener)(Event::Misc(None, String::from("ERR_KEYNOTSET"), args, suffix)),
-            Response::ERR_KEYNOPERMISSION => (self.listener)(Event::Misc(None, String::from("ERR_KEYNOPERMISSION"), args, suffix)),
-            Response::ERR_NICKLOCKED => (self.listener)(Event::Misc(None, String::from("ERR_NICKLOCKED"), args, suffix)),
-            Response::ERR_SASLFAIL => (self.listener)(Event::Misc(None, String::from("ERR_SASLFAIL"), args, suffix)),
-            Response::ERR_SASLTOOLONG => (self.listener)(Event::Misc(None, String::from("ERR_SASLTOOLONG"), args, suffix)),
-            Response::ERR_SASLABORT => (self.listener)(Event::Misc(None, String::from("ERR_SASLABORT"), args, suffix)),
-            Response::ERR_SASLALREADY => (self.listener)(Event::Misc(None, String::from("ERR_SASLALREADY"), args, suffix)),
-            _ => ()
-        }
-    }
-}
\ No newline at end of file
+            _ => {
+                if let Some(kind) = ServerErrorKind::from(res) {
+                    self.server_error(kind, args, suffix)
+                }
+            }
+        }
+    }
+
+    fn server_error(&mut self, kind: ServerErrorKind, args: Vec<String>, suffix: Option<String>) {
+        // the first argument is always our own nick, the second is what the error is about
+        let target = args.into_iter().nth(1);
+        let whois_failed = kind == ServerErrorKind::NoSuchNick || kind == ServerErrorKind::NoSuchServer;
+        if let (true, Some(target)) = (whois_failed, target.as_ref()) {
+            let key = self.key(target);
+            if let Some(info) = self.whois.fail(&key, kind) {
+                (self.listener)(Event::Whois(info));
+            }
+        }
+        if let Some(ref target) = target {
+            // 403 and 477 also answer MODE, PART, PRIVMSG and the like, only
+            // a channel we are waiting to get into has failed
+            let joining = self.channel(target).map(|ch| ch.membership() == Membership::Joining).unwrap_or(false);
+            if joining && (kind.is_join_failure() || kind == ServerErrorKind::UnavailableResource) {
+                self.set_own_membership(target, Membership::Failed(kind));
+            }
+        }
+        match kind {
+            // while registering we work through the alternates and then the
+            // suffixed nicks. Once registered it can only be the answer to a
+            // reclaim or a NICK of our own and we keep the nick we have
+            ServerErrorKind::NicknameInUse | ServerErrorKind::ErroneousNickname if self.nick.current().is_none() => {
+                if let Some(next) = self.nick.rejected() {
+                    (self.sender)(Command::NICK(next));
+                }
+            },
+            ServerErrorKind::NickCollision => {
+                if let Some(next) = self.nick.rejected() {
+                    (self.sender)(Command::NICK(next));
+                }
+            },
+            // 907 means an earlier login already worked
+            ServerErrorKind::SaslAlready => {
+                if let Some(ref mut sasl) = self.sasl {
+                    sasl.succeeded();
+                }
+                self.sasl_finished();
+            },
+            ServerErrorKind::SaslFail
+            | ServerErrorKind::SaslTooLong
+            | ServerErrorKind::SaslAbort
+            | ServerErrorKind::NickLocked => {
+                if let Some(ref mut sasl) = self.sasl {
+                    sasl.failed();
+                }
+                self.sasl_finished();
+            },
+            _ if kind.is_fatal() => self.set_connection_status(ConnectionStatus::NotConnected),
+            _ => (),
+        }
+        (self.listener)(Event::ServerError {
+            kind,
+            target,
+            message: suffix.unwrap_or_default(),
+            retryable: kind.retryable(),
+        })
+    }
+}
+
+// when something happened, the server's idea of it if it sent one
+fn time_stamp(tags: &MessageTags) -> u64 {
+    tags.server_time().unwrap_or_else(now)
+}
+
+// the parameters after the command, with the trailing one kept apart
+fn wire_params(line: &str) -> (Vec<String>, Option<String>) {
+    let mut rest = line.trim_end_matches(['\r', '\n']);
+    let skip = |rest: &mut &str| {
+        let (_, after) = rest.trim_start_matches(' ').split_once(' ').unwrap_or((rest, ""));
+        *rest = after;
+    };
+    // tags, the source and then the command itself
+    if rest.starts_with('@') {
+        skip(&mut rest);
+    }
+    if rest.trim_start_matches(' ').starts_with(':') {
+        skip(&mut rest);
+    }
+    skip(&mut rest);
+    let mut params = Vec::new();
+    loop {
+        rest = rest.trim_start_matches(' ');
+        if rest.is_empty() {
+            return (params, None);
+        }
+        if let Some(trailing) = rest.strip_prefix(':') {
+            return (params, Some(String::from(trailing)));
+        }
+        let (param, after) = rest.split_once(' ').unwrap_or((rest, ""));
+        params.push(String::from(param));
+        rest = after;
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or(Duration::new(0, 0)).as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use super::*;
+
+    fn server(lines: &[&str]) -> Server {
+        let mut server = Server::new();
+        for line in lines {
+            server.handle_line(line);
+        }
+        server
+    }
+
+    // everything the server sends, as it would go over the wire
+    fn sent(server: &mut Server) -> Rc<RefCell<Vec<String>>> {
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let lines = sent.clone();
+        server.set_sender(Box::new(move |cmd| {
+            lines.borrow_mut().push(Message::from(cmd).to_string().trim_end().to_string())
+        }));
+        sent
+    }
+
+    fn events(server: &mut Server) -> Rc<RefCell<Vec<Event>>> {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let seen = events.clone();
+        server.listener = Box::new(move |ev| seen.borrow_mut().push(ev));
+        events
+    }
+
+    fn joins(sent: &Rc<RefCell<Vec<String>>>) -> Vec<String> {
+        sent.borrow_mut().drain(..).filter(|l| l.starts_with("JOIN")).collect()
+    }
+
+    #[test]
+    fn wire_params_keep_every_argument() {
+        assert_eq!(wire_params("@time=x :op!u@h MODE #c +fo #fwd nick\r\n"), (
+            vec![String::from("#c"), String::from("+fo"), String::from("#fwd"), String::from("nick")],
+            None,
+        ));
+        assert_eq!(wire_params("MODE #c +b :*!*@host"), (
+            vec![String::from("#c"), String::from("+b")],
+            Some(String::from("*!*@host")),
+        ));
+    }
+
+    #[test]
+    fn channel_mode_from_the_wire() {
+        let server = server(&[
+            ":srv 001 bot :Welcome",
+            ":srv 005 bot CHANMODES=beI,kf,l,imnpst :are supported",
+            ":bot!u@h JOIN #c",
+            ":nick!u@h JOIN #c",
+            ":op!u@h MODE #c +fo #fwd nick",
+        ]);
+        let channel = server.channel("#c").expect("channel");
+        assert_eq!(channel.modes().forward(), Some("#fwd"));
+        assert!(channel.user("nick").expect("nick").has_privilege('o'));
+    }
+
+    #[test]
+    fn rejoin_survives_repeated_drops() {
+        let mut server = Server::new();
+        let sent = sent(&mut server);
+        server.join_on_connect("#keyed", Some("secret"));
+        server.join_on_connect("#open", None);
+        server.handle_line(":srv 001 bot :Welcome");
+        assert_eq!(joins(&sent), vec!["JOIN #keyed,#open secret"]);
+        server.handle_line(":bot!u@h JOIN #open");
+        // dropped before the server answered the JOIN for #keyed
+        server.disconnected(None);
+        server.handle_line(":srv 001 bot :Welcome");
+        assert_eq!(joins(&sent), vec!["JOIN #keyed,#open secret"]);
+        server.disconnected(None);
+        server.handle_line(":srv 001 bot :Welcome");
+        assert_eq!(joins(&sent), vec!["JOIN #keyed,#open secret"]);
+        server.handle_line(":bot!u@h JOIN #keyed");
+        server.handle_line(":bot!u@h JOIN #open");
+        server.disconnected(None);
+        server.handle_line(":srv 001 bot :Welcome");
+        assert_eq!(joins(&sent).len(), 1);
+    }
+
+    #[test]
+    fn case_mapping_change_files_things_again() {
+        let mut server = server(&[
+            ":srv 001 bot :Welcome",
+            ":bot!u@h JOIN #Chan~",
+            ":Dan~!u@h JOIN #Chan~",
+            ":Dan~!u@h PRIVMSG bot :hi",
+        ]);
+        // rfc1459 until the server says otherwise, so ~ and ^ are the same
+        assert!(server.channel("#chan^").is_some());
+        assert!(server.query("DAN^").is_some());
+        assert!(server.channel("#chan~").and_then(|ch| ch.user("dan^")).is_some());
+
+        server.handle_line(":srv 005 bot CASEMAPPING=strict-rfc1459 :are supported");
+        assert!(server.channel("#chan^").is_none());
+        assert!(server.query("DAN^").is_none());
+        let channel = server.channel("#CHAN~").expect("channel");
+        assert_eq!(channel.name(), "#Chan~");
+        assert!(channel.user("dan^").is_none());
+        assert!(channel.user("DAN~").is_some());
+        assert_eq!(server.query("dan~").expect("query").nick(), "Dan~");
+
+        server.handle_line(":srv 005 bot CASEMAPPING=ascii :are supported");
+        assert!(server.channel("#chan~").is_some());
+        assert!(server.query("dan~").is_some());
+    }
+
+    #[test]
+    fn nick_in_use_only_while_registering() {
+        let mut server = Server::new();
+        server.set_nick_state(NickState::with_alternates("fruitbot", vec![String::from("fbot")]));
+        let sent = sent(&mut server);
+        server.handle_line(":srv 433 * fruitbot :Nickname is already in use");
+        assert_eq!(sent.borrow_mut().drain(..).collect::<Vec<_>>(), vec!["NICK :fbot"]);
+        server.handle_line(":srv 001 fbot :Welcome");
+        server.handle_line(":srv 005 fbot NICKLEN=6 :are supported");
+        sent.borrow_mut().clear();
+        // the answer to a reclaim, we keep the nick we have
+        server.handle_line(":srv 433 fbot fruitbot :Nickname is already in use");
+        server.handle_line(":srv 432 fbot fruitbot :Erroneous nickname");
+        assert!(sent.borrow().is_empty());
+        assert_eq!(server.nick.current(), Some("fbot"));
+
+        // the next connection starts over, cut to the NICKLEN we last saw
+        server.disconnected(None);
+        server.handle_line(":srv 433 * fruitbot :Nickname is already in use");
+        server.handle_line(":srv 433 * fbot :Nickname is already in use");
+        assert_eq!(sent.borrow_mut().drain(..).collect::<Vec<_>>(), vec!["NICK :fbot", "NICK :fruit_"]);
+    }
+
+    #[test]
+    fn names_for_other_channels_are_not_kept() {
+        let mut server = server(&[":srv 001 bot :Welcome", ":bot!u@h JOIN #mine"]);
+        let events = events(&mut server);
+        for line in &[
+            ":srv 353 bot = #other :@alice +bob!b@host carol",
+            ":srv 366 bot #other :End of /NAMES list.",
+            ":srv 353 bot = #mine :bot @dave",
+            ":srv 366 bot #mine :End of /NAMES list.",
+        ] {
+            server.handle_line(line);
+        }
+        assert!(server.channel("#other").is_none());
+        assert!(!server.get_state().contains("#other"));
+        assert!(server.channel("#mine").and_then(|ch| ch.user("dave")).is_some());
+        let users: Vec<(String, Vec<String>)> = events.borrow().iter().filter_map(|ev| match *ev {
+            Event::NewUsers(ref channel, ref users) => {
+                let mut users = users.clone();
+                users.sort();
+                Some((channel.clone(), users))
+            },
+            _ => None,
+        }).collect();
+        assert_eq!(users, vec![
+            (String::from("#other"), vec![String::from("alice"), String::from("bob"), String::from("carol")]),
+            (String::from("#mine"), vec![String::from("bot"), String::from("dave")]),
+        ]);
+    }
+
+    #[test]
+    fn status_messages_go_to_the_channel() {
+        let mut server = server(&[
+            ":srv 001 bot :Welcome",
+            ":srv 005 bot STATUSMSG=@+ :are supported",
+            ":bot!u@h JOIN #rust",
+        ]);
+        let events = events(&mut server);
+        server.handle_line(":alice!a@host PRIVMSG @#rust :ops only");
+        server.handle_line(":bot!u@h PRIVMSG #elsewhere :echo from outside");
+        assert!(server.query("alice").is_none());
+        assert!(server.channel("#elsewhere").is_none());
+        assert!(server.get_state().contains("ops only"));
+        assert!(!server.get_state().contains("echo from outside"));
+        let targets: Vec<String> = events.borrow().iter().filter_map(|ev| match *ev {
+            Event::NewMessage(ref target, _) => Some(target.clone()),
+            _ => None,
+        }).collect();
+        assert_eq!(targets, vec!["#rust", "#elsewhere"]);
+    }
+
+    #[test]
+    fn every_status_change_is_reported() {
+        let mut server = Server::new();
+        let events = events(&mut server);
+        server.handle_line(":srv NOTICE AUTH :*** Looking up your hostname");
+        server.handle_line(":srv 001 bot :Welcome");
+        server.handle_line(":srv PONG srv :not-ours");
+        server.disconnected(None);
+        server.handle_line(":srv 001 bot :Welcome");
+        server.handle_line(":srv 465 bot :You are banned");
+        let statuses: Vec<ConnectionStatus> = events.borrow().iter().filter_map(|ev| match *ev {
+            Event::Status(status) => Some(status),
+            _ => None,
+        }).collect();
+        assert_eq!(statuses, vec![
+            ConnectionStatus::Authenticating,
+            ConnectionStatus::Connected,
+            ConnectionStatus::NotConnected,
+            ConnectionStatus::Connected,
+            ConnectionStatus::NotConnected,
+        ]);
+        assert!(events.borrow().iter().any(|ev| matches!(*ev, Event::Pong { .. })));
+    }
+
+    #[test]
+    fn no_ctcp_replies_to_overflowed_history() {
+        let mut server = server(&[":srv 001 bot :Welcome", ":bot!u@h JOIN #c"]);
+        server.set_ctcp_responder(CtcpResponder::new("bot 1.0", "source"));
+        let sent = sent(&mut server);
+        server.handle_line(":srv BATCH +h chathistory #c");
+        server.handle_line("@batch=h :early!u@h PRIVMSG bot :\u{1}VERSION\u{1}");
+        for i in 0..10_000 {
+            server.handle_line(&format!("@batch=h :alice!u@h PRIVMSG #c :line {}", i));
+        }
+        // the batch has been handed over by now, the rest arrives live
+        server.handle_line("@batch=h :late!u@h PRIVMSG bot :\u{1}VERSION\u{1}");
+        server.handle_line(":srv BATCH -h");
+        let notices: Vec<String> = sent.borrow().iter().filter(|l| l.starts_with("NOTICE")).cloned().collect();
+        assert!(notices.is_empty(), "{:?}", notices);
+        server.handle_line(":live!u@h PRIVMSG bot :\u{1}VERSION\u{1}");
+        assert_eq!(sent.borrow().iter().filter(|l| l.starts_with("NOTICE live")).count(), 1);
+    }
+}
@@ -0,0 +1,169 @@
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter, Result};
+
+use chrono::DateTime;
+use irc::proto::message::Tag;
+
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct MessageTags {
+    tags: BTreeMap<String, Option<String>>,
+}
+
+impl MessageTags {
+    pub fn from(tags: Option<Vec<Tag>>) -> MessageTags {
+        let tags = tags.unwrap_or_default()
+            .into_iter()
+            .map(|Tag(key, value)| {
+                // an empty value is the same as no value at all
+                let value = value.map(|v| unescape(&v)).filter(|v| !v.is_empty());
+                (key, value)
+            })
+            .collect();
+        MessageTags {
+            tags,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tags.is_empty()
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.tags.contains_key(key)
+    }
+
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.tags.get(key).and_then(|v| v.as_deref())
+    }
+
+    pub fn server_time(&self) -> Option<u64> {
+        let time = self.get("time")?;
+        DateTime::parse_from_rfc3339(time).ok().map(|t| t.timestamp().max(0) as u64)
+    }
+
+    pub fn msg_id(&self) -> Option<&str> {
+        self.get("msgid")
+    }
+
+    pub fn account(&self) -> Option<&str> {
+        self.get("account")
+    }
+
+    pub fn label(&self) -> Option<&str> {
+        self.get("label")
+    }
+
+    pub fn batch(&self) -> Option<&str> {
+        self.get("batch")
+    }
+
+    pub fn describe(&self) -> Option<String> {
+        if self.is_empty() {
+            None
+        } else {
+            Some(self.to_string())
+        }
+    }
+}
+
+impl Display for MessageTags {
+    fn fmt(&self, f: &mut Formatter) -> Result {
+        let tags: Vec<String> = self.tags.iter().map(|(key, value)| match *value {
+            Some(ref value) => format!("{}={}", key, escape(value)),
+            None => key.clone(),
+        }).collect();
+        write!(f, "{}", tags.join(";"))
+    }
+}
+
+pub fn unescape(value: &str) -> String {
+    let mut ret = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            ret.push(c);
+            continue;
+        }
+        // a trailing backslash on its own is dropped
+        match chars.next() {
+            Some(':') => ret.push(';'),
+            Some('s') => ret.push(' '),
+            Some('r') => ret.push('\r'),
+            Some('n') => ret.push('\n'),
+            Some(other) => ret.push(other),
+            None => (),
+        }
+    }
+    ret
+}
+
+pub fn escape(value: &str) -> String {
+    let mut ret = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ';' => ret.push_str("\\:"),
+            ' ' => ret.push_str("\\s"),
+            '\\' => ret.push_str("\\\\"),
+            '\r' => ret.push_str("\\r"),
+            '\n' => ret.push_str("\\n"),
+            _ => ret.push(c),
+        }
+    }
+    ret
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use irc::proto::Message;
+
+    fn tags(line: &str) -> MessageTags {
+        MessageTags::from(line.parse::<Message>().expect("message").tags)
+    }
+
+    #[test]
+    fn unescape_values() {
+        assert_eq!(unescape("a\\:b\\sc\\\\d\\re\\nf"), "a;b c\\d\re\nf");
+        // an unknown escape is just the character, a lone trailing backslash goes
+        assert_eq!(unescape("\\x\\"), "x");
+        assert_eq!(unescape("plain"), "plain");
+        assert_eq!(unescape(""), "");
+    }
+
+    #[test]
+    fn escape_round_trips() {
+        let value = "a;b c\\d\re\nf";
+        assert_eq!(escape(value), "a\\:b\\sc\\\\d\\re\\nf");
+        assert_eq!(unescape(&escape(value)), value);
+    }
+
+    #[test]
+    fn from_a_message() {
+        let tags = tags("@msgid=abc;label=x\\sy;+example.com/flag;account= :nick!u@h PRIVMSG #c :hi");
+        assert_eq!(tags.msg_id(), Some("abc"));
+        assert_eq!(tags.label(), Some("x y"));
+        assert!(tags.contains("+example.com/flag"));
+        assert_eq!(tags.get("+example.com/flag"), None);
+        // an empty value is the same as none
+        assert!(tags.contains("account"));
+        assert_eq!(tags.account(), None);
+        assert_eq!(tags.batch(), None);
+        assert!(MessageTags::from(None).is_empty());
+        assert_eq!(MessageTags::from(None).describe(), None);
+    }
+
+    #[test]
+    fn display_escapes() {
+        let tags = tags("@label=x\\sy;msgid=1 :nick!u@h PRIVMSG #c :hi");
+        assert_eq!(tags.to_string(), "label=x\\sy;msgid=1");
+    }
+
+    #[test]
+    fn server_time() {
+        assert_eq!(tags("@time=2019-02-03T04:05:06.789Z :n!u@h PRIVMSG #c :hi").server_time(), Some(1_549_166_706));
+        assert_eq!(tags("@time=2019-02-03T05:05:06+01:00 :n!u@h PRIVMSG #c :hi").server_time(), Some(1_549_166_706));
+        assert_eq!(tags("@time=yesterday :n!u@h PRIVMSG #c :hi").server_time(), None);
+        assert_eq!(tags("@time=1969-12-31T00:00:00Z :n!u@h PRIVMSG #c :hi").server_time(), Some(0));
+        assert_eq!(tags(":n!u@h PRIVMSG #c :hi").server_time(), None);
+    }
+}
@@ -0,0 +1,169 @@
+use hostmask::Hostmask;
+
+// what we ask for with WHOX and the token that marks the replies as ours,
+// tokens can be at most three digits
+pub const WHOX_FIELDS: &str = "tcuhsnfdar";
+pub const WHOX_TOKEN: &str = "616";
+// servers always send the WHOX fields in this order, whatever order we asked in
+const WHOX_ORDER: &str = "tcuihsnfdlaor";
+
+// one line of a WHO or WHOX reply
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct WhoReply {
+    // None when the user was matched by something other than a channel
+    pub channel: Option<String>,
+    pub nick: String,
+    pub user: Option<String>,
+    pub host: Option<String>,
+    pub server: Option<String>,
+    pub away: bool,
+    pub oper: bool,
+    // prefix symbols from the flags, e.g. "@+"
+    pub prefixes: String,
+    pub hop_count: Option<u32>,
+    pub account: Option<String>,
+    pub real_name: Option<String>,
+}
+
+impl WhoReply {
+    // RPL_WHOREPLY <me> <channel> <user> <host> <server> <nick> <flags> :<hop count> <real name>
+    pub fn parse(args: &[String], suffix: Option<&str>) -> Option<WhoReply> {
+        if args.len() < 7 {
+            return None;
+        }
+        let mut reply = WhoReply {
+            channel: channel(&args[1]),
+            nick: args[5].clone(),
+            user: Some(args[2].clone()),
+            host: Some(args[3].clone()),
+            server: Some(args[4].clone()),
+            ..WhoReply::default()
+        };
+        reply.flags(&args[6]);
+        if let Some(suffix) = suffix {
+            let mut parts = suffix.splitn(2, ' ');
+            reply.hop_count = parts.next().and_then(|h| h.parse().ok());
+            reply.real_name = parts.next().map(String::from);
+        }
+        Some(reply)
+    }
+
+    // RPL_WHOSPCRPL <me> <fields...>, only the ones asked for and the real
+    // name as the trailing part. Anything without our token belongs to
+    // somebody else's WHOX
+    pub fn parse_whox(fields: &str, token: &str, args: &[String], suffix: Option<&str>) -> Option<WhoReply> {
+        let mut values = args.iter().skip(1).map(|a| a.as_str()).chain(suffix);
+        let mut reply = WhoReply::default();
+        for field in WHOX_ORDER.chars().filter(|f| fields.contains(*f)) {
+            let value = values.next()?;
+            match field {
+                't' if value != token => return None,
+                'c' => reply.channel = channel(value),
+                'u' => reply.user = Some(String::from(value)),
+                'h' => reply.host = Some(String::from(value)),
+                's' => reply.server = Some(String::from(value)),
+                'n' => reply.nick = String::from(value),
+                'f' => reply.flags(value),
+                'd' => reply.hop_count = value.parse().ok(),
+                // 0 means not logged in
+                'a' if value != "0" => reply.account = Some(String::from(value)),
+                'r' => reply.real_name = Some(String::from(value)),
+                _ => (),
+            }
+        }
+        if reply.nick.is_empty() {
+            return None;
+        }
+        Some(reply)
+    }
+
+    // H or G for here or gone, * for an oper then any channel prefixes
+    fn flags(&mut self, flags: &str) {
+        for c in flags.chars() {
+            match c {
+                'H' => self.away = false,
+                'G' => self.away = true,
+                '*' => self.oper = true,
+                c if !c.is_ascii_alphanumeric() => self.prefixes.push(c),
+                _ => (),
+            }
+        }
+    }
+
+    pub fn hostmask(&self) -> Hostmask {
+        Hostmask::new(&self.nick, self.user.as_deref(), self.host.as_deref())
+    }
+}
+
+fn channel(value: &str) -> Option<String> {
+    if value == "*" {
+        None
+    } else {
+        Some(String::from(value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| String::from(*a)).collect()
+    }
+
+    #[test]
+    fn parse_who() {
+        let reply = WhoReply::parse(&args(&["me", "#rust", "~bob", "example.com", "irc.example.com", "Bob", "G*@+"]), Some("3 Bob Smith"))
+            .expect("reply");
+        assert_eq!(reply.channel.as_deref(), Some("#rust"));
+        assert_eq!(reply.nick, "Bob");
+        assert_eq!(reply.hostmask().to_string(), "Bob!~bob@example.com");
+        assert!(reply.away && reply.oper);
+        assert_eq!(reply.prefixes, "@+");
+        assert_eq!(reply.hop_count, Some(3));
+        assert_eq!(reply.real_name.as_deref(), Some("Bob Smith"));
+        assert!(WhoReply::parse(&args(&["me", "*", "~bob"]), None).is_none());
+    }
+
+    #[test]
+    fn parse_whox_fields() {
+        let line = args(&["me", "616", "#rust", "~bob", "example.com", "irc.example.com", "Bob", "H@", "0", "bobby"]);
+        let reply = WhoReply::parse_whox(WHOX_FIELDS, WHOX_TOKEN, &line, Some("Bob Smith")).expect("reply");
+        assert_eq!(reply, WhoReply {
+            channel: Some(String::from("#rust")),
+            nick: String::from("Bob"),
+            user: Some(String::from("~bob")),
+            host: Some(String::from("example.com")),
+            server: Some(String::from("irc.example.com")),
+            away: false,
+            oper: false,
+            prefixes: String::from("@"),
+            hop_count: Some(0),
+            account: Some(String::from("bobby")),
+            real_name: Some(String::from("Bob Smith")),
+        });
+    }
+
+    #[test]
+    fn parse_whox_order() {
+        // fields come back in the server's order, not the order we asked in
+        let line = args(&["me", "616", "Bob", "0"]);
+        let reply = WhoReply::parse_whox("atn", WHOX_TOKEN, &line, None).expect("reply");
+        assert_eq!(reply.nick, "Bob");
+        assert_eq!(reply.account, None);
+        assert_eq!(reply.channel, None);
+    }
+
+    #[test]
+    fn parse_whox_rejects() {
+        // someone else's token
+        let line = args(&["me", "123", "Bob"]);
+        assert!(WhoReply::parse_whox("tn", WHOX_TOKEN, &line, None).is_none());
+        // too few fields
+        let line = args(&["me", "616"]);
+        assert!(WhoReply::parse_whox("tn", WHOX_TOKEN, &line, None).is_none());
+        // no nick asked for
+        let line = args(&["me", "616", "#rust"]);
+        assert!(WhoReply::parse_whox("tc", WHOX_TOKEN, &line, None).is_none());
+    }
+}
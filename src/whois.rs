@@ -0,0 +1,186 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use casemap::CaseKey;
+use error::ServerErrorKind;
+
+const DEFAULT_TTL: Duration = Duration::from_secs(300);
+// a WHOIS the server never finished answering
+const PENDING_TIMEOUT: Duration = Duration::from_secs(60);
+
+// the numerics that make up a WHOIS reply, the ones the irc crate doesn't
+// know come in as Raw
+pub const RPL_WHOISCERTFP: u16 = 276;
+pub const RPL_AWAY: u16 = 301;
+pub const RPL_WHOISREGNICK: u16 = 307;
+pub const RPL_WHOISUSER: u16 = 311;
+pub const RPL_WHOISSERVER: u16 = 312;
+pub const RPL_WHOISOPERATOR: u16 = 313;
+pub const RPL_WHOISIDLE: u16 = 317;
+pub const RPL_ENDOFWHOIS: u16 = 318;
+pub const RPL_WHOISCHANNELS: u16 = 319;
+pub const RPL_WHOISSPECIAL: u16 = 320;
+pub const RPL_WHOISACCOUNT: u16 = 330;
+pub const RPL_WHOISBOT: u16 = 335;
+pub const RPL_WHOISACTUALLY: u16 = 338;
+pub const RPL_WHOISHOST: u16 = 378;
+pub const RPL_WHOISMODES: u16 = 379;
+pub const RPL_WHOISSECURE: u16 = 671;
+
+#[derive(Debug, Clone, Default, Serialize, PartialEq)]
+pub struct WhoisInfo {
+    pub nick: String,
+    pub user: Option<String>,
+    pub host: Option<String>,
+    pub real_name: Option<String>,
+    pub server: Option<String>,
+    pub server_info: Option<String>,
+    pub account: Option<String>,
+    pub away: Option<String>,
+    // channels with their prefixes, e.g. "@#rust"
+    pub channels: Vec<String>,
+    pub idle: Option<u64>,
+    pub signon: Option<u64>,
+    pub operator: bool,
+    pub secure: bool,
+    pub bot: bool,
+    pub cert_fp: Option<String>,
+    pub actual_host: Option<String>,
+    // lines we don't pick apart, like RPL_WHOISSPECIAL
+    pub extra: Vec<String>,
+    pub error: Option<ServerErrorKind>,
+}
+
+impl WhoisInfo {
+    pub fn with_nick(nick: &str) -> WhoisInfo {
+        WhoisInfo {
+            nick: String::from(nick),
+            ..WhoisInfo::default()
+        }
+    }
+
+    // args start with our own nick then the nick being looked up
+    pub fn apply(&mut self, code: u16, args: &[String], suffix: Option<&str>) {
+        let arg = |i: usize| args.get(i).cloned();
+        match code {
+            RPL_WHOISUSER => {
+                // the nick as the server spells it
+                self.nick = arg(1).unwrap_or_default();
+                self.user = arg(2);
+                self.host = arg(3);
+                self.real_name = suffix.map(String::from);
+            },
+            RPL_WHOISSERVER => {
+                self.server = arg(2);
+                self.server_info = suffix.map(String::from);
+            },
+            RPL_WHOISOPERATOR => self.operator = true,
+            RPL_WHOISIDLE => {
+                self.idle = arg(2).and_then(|i| i.parse().ok());
+                self.signon = arg(3).and_then(|s| s.parse().ok());
+            },
+            // long channel lists are split over several lines
+            RPL_WHOISCHANNELS => self.channels.extend(
+                suffix.unwrap_or("").split(' ').filter(|c| !c.is_empty()).map(String::from)
+            ),
+            RPL_WHOISACCOUNT => self.account = arg(2),
+            RPL_AWAY => self.away = Some(String::from(suffix.unwrap_or(""))),
+            RPL_WHOISSECURE => self.secure = true,
+            RPL_WHOISBOT => self.bot = true,
+            // "has client certificate fingerprint <fp>"
+            RPL_WHOISCERTFP => self.cert_fp = suffix.and_then(|s| s.rsplit(' ').next()).map(String::from),
+            // some servers send the host, some user@host and an ip
+            RPL_WHOISACTUALLY => self.actual_host = Some(args.iter().skip(2).cloned().collect::<Vec<_>>().join(" ")),
+            _ => self.extra.extend(suffix.map(String::from)),
+        }
+    }
+}
+
+// the numeric of a Raw command, if it is part of a WHOIS reply
+pub fn whois_code(command: &str) -> Option<u16> {
+    command.parse().ok().filter(|code| is_whois_line(*code))
+}
+
+pub fn is_whois_line(code: u16) -> bool {
+    matches!(code,
+        RPL_WHOISCERTFP | RPL_WHOISREGNICK | RPL_WHOISUSER | RPL_WHOISSERVER
+        | RPL_WHOISOPERATOR | RPL_WHOISIDLE | RPL_WHOISCHANNELS | RPL_WHOISSPECIAL
+        | RPL_WHOISACCOUNT | RPL_WHOISBOT | RPL_WHOISACTUALLY | RPL_WHOISHOST
+        | RPL_WHOISMODES | RPL_WHOISSECURE)
+}
+
+// replies being put together and finished ones we can answer from
+#[derive(Debug)]
+pub struct WhoisCache {
+    pending: HashMap<CaseKey, (Instant, WhoisInfo)>,
+    done: HashMap<CaseKey, (Instant, WhoisInfo)>,
+    ttl: Duration,
+}
+
+impl Default for WhoisCache {
+    fn default() -> Self {
+        WhoisCache::new()
+    }
+}
+
+impl WhoisCache {
+    pub fn new() -> WhoisCache {
+        WhoisCache {
+            pending: HashMap::new(),
+            done: HashMap::new(),
+            ttl: DEFAULT_TTL,
+        }
+    }
+
+    pub fn set_ttl(&mut self, ttl: Duration) {
+        self.ttl = ttl;
+    }
+
+    pub fn get(&self, key: &CaseKey, now: Instant) -> Option<&WhoisInfo> {
+        match self.done.get(key) {
+            Some(&(at, ref info)) if now.duration_since(at) < self.ttl => Some(info),
+            _ => None,
+        }
+    }
+
+    pub fn is_pending(&self, key: &CaseKey) -> bool {
+        self.pending.contains_key(key)
+    }
+
+    pub fn start(&mut self, key: CaseKey, nick: &str, now: Instant) {
+        self.pending.insert(key, (now, WhoisInfo::with_nick(nick)));
+    }
+
+    // a WHOIS sent without going through Server::whois is still collected
+    pub fn line(&mut self, key: CaseKey, nick: &str, code: u16, args: &[String], suffix: Option<&str>, now: Instant) {
+        self.pending.entry(key)
+            .or_insert_with(|| (now, WhoisInfo::with_nick(nick)))
+            .1
+            .apply(code, args, suffix);
+    }
+
+    pub fn finish(&mut self, key: &CaseKey, now: Instant) -> Option<WhoisInfo> {
+        let (_, info) = self.pending.remove(key)?;
+        self.done.insert(key.clone(), (now, info.clone()));
+        Some(info)
+    }
+
+    // errors aren't cached, the nick may well exist next time we ask
+    pub fn fail(&mut self, key: &CaseKey, kind: ServerErrorKind) -> Option<WhoisInfo> {
+        let (_, mut info) = self.pending.remove(key)?;
+        info.error = Some(kind);
+        self.done.remove(key);
+        Some(info)
+    }
+
+    pub fn expire(&mut self, now: Instant) {
+        let ttl = self.ttl;
+        self.done.retain(|_, &mut (at, _)| now.duration_since(at) < ttl);
+        self.pending.retain(|_, &mut (at, _)| now.duration_since(at) < PENDING_TIMEOUT);
+    }
+
+    pub fn clear(&mut self) {
+        self.pending.clear();
+        self.done.clear();
+    }
+}